@@ -96,6 +96,24 @@ in
         };
       };
 
+      volumeDir = lib.mkOption {
+        type = lib.types.path;
+        default = "${cfg.dataDir}/volumes";
+        example = "/var/lib/xnode-manager/volumes";
+        description = ''
+          The directory to store named container volumes.
+        '';
+      };
+
+      templateDir = lib.mkOption {
+        type = lib.types.path;
+        default = "${cfg.dataDir}/templates";
+        example = "/var/lib/xnode-manager/templates";
+        description = ''
+          The directory to store container templates.
+        '';
+      };
+
       backupDir = lib.mkOption {
         type = lib.types.path;
         default = "${cfg.dataDir}/backups";
@@ -105,6 +123,26 @@ in
         '';
       };
 
+      hostFileAccess = {
+        allow = lib.mkOption {
+          type = lib.types.listOf lib.types.path;
+          default = [ "/" ];
+          example = [ "/var/lib" ];
+          description = ''
+            Host paths (including everything below them) that can be accessed through the file API.
+          '';
+        };
+
+        deny = lib.mkOption {
+          type = lib.types.listOf lib.types.path;
+          default = [ ];
+          example = [ "/etc/shadow" ];
+          description = ''
+            Host paths (including everything below them) that can not be accessed through the file API, even if allowed.
+          '';
+        };
+      };
+
       commandstream = lib.mkOption {
         type = lib.types.path;
         default = "${cfg.dataDir}/commandstream";
@@ -123,6 +161,15 @@ in
         '';
       };
 
+      archiveLimit = lib.mkOption {
+        type = lib.types.ints.positive;
+        default = 16 * 1024 * 1024 * 1024;
+        example = 1024 * 1024 * 1024;
+        description = ''
+          Maximum size in bytes of an archive uploaded to extract or import, larger uploads are rejected.
+        '';
+      };
+
       nix = lib.mkOption {
         type = lib.types.package;
         default = pkgs.nix;
@@ -167,19 +214,30 @@ in
         CONTAINERPROFILE = cfg.container.profile;
         CONTAINERCONFIG = cfg.container.config;
         SYSTEMDCONFIG = cfg.container.systemd-config;
+        VOLUMEDIR = cfg.volumeDir;
+        TEMPLATEDIR = cfg.templateDir;
         BACKUPDIR = cfg.backupDir;
+        HOSTFILEALLOW = lib.concatStringsSep ":" cfg.hostFileAccess.allow;
+        HOSTFILEDENY = lib.concatStringsSep ":" cfg.hostFileAccess.deny;
         COMMANDSTREAM = cfg.commandstream;
         BUILDCORES = toString cfg.buildCores;
+        ARCHIVELIMIT = toString cfg.archiveLimit;
         NIX = "${cfg.nix}/bin/";
         NIXOSREBUILD = "${cfg.nixos-rebuild}/bin/";
         SYSTEMD = "${cfg.systemd}/bin/";
         E2FSPROGS = "${pkgs.e2fsprogs}/bin/";
+        COREUTILS = "${pkgs.coreutils}/bin/";
+        GNUTAR = "${pkgs.gnutar}/bin/";
+        ZSTD = "${pkgs.zstd}/bin/";
+        LIBARCHIVE = "${pkgs.libarchive}/bin/";
+        B3SUM = "${pkgs.b3sum}/bin/";
       };
       serviceConfig = {
         ExecStart = "${lib.getExe xnode-manager}";
         User = "root";
         Group = "root";
         StateDirectory = "xnode-manager";
+        PrivateTmp = true;
         Restart = "always";
       };
     };
@@ -0,0 +1,268 @@
+use std::{fs, io, path::Path};
+
+// nixos-container configuration file (/etc/nixos-containers/<id>.conf), loaded as systemd EnvironmentFile by container@.service
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContainerConf {
+    pub private_network: Option<bool>,
+    pub host_address: Option<String>,
+    pub local_address: Option<String>,
+    pub host_address6: Option<String>,
+    pub local_address6: Option<String>,
+    pub host_bridge: Option<String>,
+    pub host_port: Option<String>,
+    pub interfaces: Option<String>,
+    pub macvlans: Option<String>,
+    pub network_namespace_path: Option<String>,
+    pub auto_start: Option<bool>,
+    pub extra_nspawn_flags: Vec<String>,
+    lines: Vec<String>, // Lines as read, rewritten in place on serialize so other keys and comments keep their position
+    trailing_newline: bool, // New files (no lines read) always end with a newline
+}
+
+const KEYS: [&str; 12] = [
+    "PRIVATE_NETWORK",
+    "HOST_ADDRESS",
+    "LOCAL_ADDRESS",
+    "HOST_ADDRESS6",
+    "LOCAL_ADDRESS6",
+    "HOST_BRIDGE",
+    "HOST_PORT",
+    "INTERFACES",
+    "MACVLANS",
+    "NETWORK_NAMESPACE_PATH",
+    "AUTO_START",
+    "EXTRA_NSPAWN_FLAGS",
+];
+
+impl ContainerConf {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path).map(|content| Self::parse(&content))
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let content = self
+            .serialize()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        fs::write(path, content)
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let mut conf = Self {
+            lines: content
+                .split_terminator("\n")
+                .map(|line| line.to_string())
+                .collect(),
+            trailing_newline: content.is_empty() || content.ends_with("\n"),
+            ..Self::default()
+        };
+        for line in &conf.lines.clone() {
+            if let Some((key, value)) = parse_line(line) {
+                conf.set_value(key, value);
+            }
+        }
+
+        conf
+    }
+
+    pub fn serialize(&self) -> Result<String, String> {
+        // The container start script expands $EXTRA_NSPAWN_FLAGS unquoted, splitting it on whitespace
+        if let Some(flag) = self
+            .extra_nspawn_flags
+            .iter()
+            .find(|flag| flag.is_empty() || flag.contains(char::is_whitespace))
+        {
+            return Err(format!(
+                "Nspawn flag \"{}\" can not be empty or contain whitespace",
+                flag
+            ));
+        }
+
+        let mut lines = vec![];
+        for (i, line) in self.lines.iter().enumerate() {
+            let Some((key, value)) = parse_line(line).filter(|(key, _)| KEYS.contains(key)) else {
+                lines.push(line.clone());
+                continue;
+            };
+
+            // Earlier assignments of a key are overridden by the last one, so left untouched
+            let last = !self.lines[i + 1..]
+                .iter()
+                .any(|other| parse_line(other).is_some_and(|(other, _)| other == key));
+            if !last {
+                lines.push(line.clone());
+                continue;
+            }
+
+            match self.value(key) {
+                Some(current) if current == normalize(key, &value) => lines.push(line.clone()),
+                Some(current) => lines.push(format!("{}={}", key, quote(&current))),
+                None => {}
+            }
+        }
+        for key in KEYS {
+            let present = self
+                .lines
+                .iter()
+                .any(|line| parse_line(line).is_some_and(|(other, _)| other == key));
+            if !present
+                && let Some(value) = self.value(key)
+                && !(key == "EXTRA_NSPAWN_FLAGS" && value.is_empty())
+            {
+                lines.push(format!("{}={}", key, quote(&value)));
+            }
+        }
+
+        let mut content = lines.join("\n");
+        if (self.trailing_newline || self.lines.is_empty()) && !lines.is_empty() {
+            content.push('\n');
+        }
+        Ok(content)
+    }
+
+    fn set_value(&mut self, key: &str, value: String) {
+        match key {
+            "PRIVATE_NETWORK" => self.private_network = Some(value == "1"),
+            "HOST_ADDRESS" => self.host_address = Some(value),
+            "LOCAL_ADDRESS" => self.local_address = Some(value),
+            "HOST_ADDRESS6" => self.host_address6 = Some(value),
+            "LOCAL_ADDRESS6" => self.local_address6 = Some(value),
+            "HOST_BRIDGE" => self.host_bridge = Some(value),
+            "HOST_PORT" => self.host_port = Some(value),
+            "INTERFACES" => self.interfaces = Some(value),
+            "MACVLANS" => self.macvlans = Some(value),
+            "NETWORK_NAMESPACE_PATH" => self.network_namespace_path = Some(value),
+            "AUTO_START" => self.auto_start = Some(value == "1"),
+            "EXTRA_NSPAWN_FLAGS" => {
+                self.extra_nspawn_flags = value.split_whitespace().map(|s| s.to_string()).collect()
+            }
+            _ => {}
+        }
+    }
+
+    // Current value of a key, in the form normalize gives for a read value
+    fn value(&self, key: &str) -> Option<String> {
+        let bool_value = |value: bool| if value { "1" } else { "0" }.to_string();
+        match key {
+            "PRIVATE_NETWORK" => self.private_network.map(bool_value),
+            "HOST_ADDRESS" => self.host_address.clone(),
+            "LOCAL_ADDRESS" => self.local_address.clone(),
+            "HOST_ADDRESS6" => self.host_address6.clone(),
+            "LOCAL_ADDRESS6" => self.local_address6.clone(),
+            "HOST_BRIDGE" => self.host_bridge.clone(),
+            "HOST_PORT" => self.host_port.clone(),
+            "INTERFACES" => self.interfaces.clone(),
+            "MACVLANS" => self.macvlans.clone(),
+            "NETWORK_NAMESPACE_PATH" => self.network_namespace_path.clone(),
+            "AUTO_START" => self.auto_start.map(bool_value),
+            "EXTRA_NSPAWN_FLAGS" => Some(self.extra_nspawn_flags.join(" ")),
+            _ => None,
+        }
+    }
+}
+
+// Key and unquoted value of an assignment, None for comments and other lines
+fn parse_line(line: &str) -> Option<(&str, String)> {
+    if line.trim_start().starts_with(['#', ';']) {
+        return None;
+    }
+
+    line.split_once("=")
+        .map(|(key, value)| (key.trim(), unquote(value.trim())))
+}
+
+fn normalize(key: &str, value: &str) -> String {
+    match key {
+        "PRIVATE_NETWORK" | "AUTO_START" => if value == "1" { "1" } else { "0" }.to_string(),
+        "EXTRA_NSPAWN_FLAGS" => value.split_whitespace().collect::<Vec<&str>>().join(" "),
+        _ => value.to_string(),
+    }
+}
+
+// EnvironmentFile value quoting: double quoted, with backslash escapes
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn unquote(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+    let mut quote: Option<char> = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => result.push(c),
+            (_, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    result.push(escaped);
+                }
+            }
+            (_, c) => result.push(c),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(content: &str) {
+        assert_eq!(
+            ContainerConf::parse(content).serialize().as_deref(),
+            Ok(content)
+        );
+    }
+
+    #[test]
+    fn round_trips_quoting() {
+        round_trip("HOST_ADDRESS=\"10.0.0.1\"\nLOCAL_ADDRESS='10.0.0.2'\nHOST_BRIDGE=br0\n");
+        round_trip("INTERFACES=\"eth0 \\\"quoted\\\" eth1\"\n");
+        round_trip("EXTRA_NSPAWN_FLAGS=\"--bind=/a  --bind-ro=/b\"\n");
+    }
+
+    #[test]
+    fn round_trips_comments_and_unknown_keys() {
+        round_trip(
+            "# Managed by hand\nSOME_KEY=value\n\nPRIVATE_NETWORK=1\n; old style comment\nAUTO_START=0\nOTHER=\"x\"\n",
+        );
+        round_trip("   # indented comment\nnot an assignment\nHOST_PORT=8080");
+    }
+
+    #[test]
+    fn round_trips_without_extra_nspawn_flags() {
+        round_trip("PRIVATE_NETWORK=1\n");
+        round_trip("");
+    }
+
+    #[test]
+    fn round_trips_duplicate_keys() {
+        round_trip("HOST_ADDRESS=10.0.0.1\nHOST_ADDRESS=10.0.0.3\n");
+    }
+
+    #[test]
+    fn replaces_values_in_place() {
+        let mut conf = ContainerConf::parse(
+            "# comment\nPRIVATE_NETWORK=1\nSOME_KEY=value\nEXTRA_NSPAWN_FLAGS=\"--a\"\nAUTO_START=1\n",
+        );
+        conf.extra_nspawn_flags = vec!["--b".to_string(), "--c".to_string()];
+        conf.auto_start = None;
+        conf.host_bridge = Some("br0".to_string());
+        assert_eq!(
+            conf.serialize().as_deref(),
+            Ok(
+                "# comment\nPRIVATE_NETWORK=1\nSOME_KEY=value\nEXTRA_NSPAWN_FLAGS=\"--b --c\"\nHOST_BRIDGE=\"br0\"\n"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_flags_with_whitespace() {
+        let conf = ContainerConf {
+            extra_nspawn_flags: vec!["--bind=/a b".to_string()],
+            ..ContainerConf::default()
+        };
+        assert!(conf.serialize().is_err());
+    }
+}
@@ -1,38 +1,67 @@
 use std::{
-    fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, write},
-    path::PathBuf,
+    collections::HashMap,
+    fs::{
+        File, create_dir_all, metadata, read_dir, read_link, read_to_string, remove_dir_all,
+        remove_file, write,
+    },
+    io::Read,
+    net::Ipv6Addr,
+    os::unix::fs::FileTypeExt,
+    path::{Component, Path, PathBuf},
     process::Command,
+    time::SystemTime,
 };
 
 use actix_web::{HttpResponse, Responder, get, post, web};
+use serde_json::{Value, json};
 
 use crate::{
-    config::models::ContainerChange,
+    config::{
+        conf::ContainerConf,
+        models::{
+            BackupSchedule, ContainerBackup, ContainerChange, ContainerClone, ContainerGeneration,
+            ContainerManifest, ContainerSettings, ContainerStatus, ContainerTemplate, Device,
+            ExportQuery, ImportQuery, Mount, MountSource, PortForward, Protocol, ResourceLimits,
+            TemplateInstance, Volume,
+        },
+    },
     request::{
         handlers::return_request_id,
         models::{RequestId, RequestIdResult},
     },
     utils::{
+        closure::closure_diff,
         command::{CommandExecutionMode, execute_command},
         env::{
-            buildcores, containerconfig, containerprofile, containersettings, containerstate,
-            e2fsprogs, nix, systemd, systemdconfig,
+            archivelimit, backupdir, buildcores, containerconfig, containerprofile,
+            containersettings, containerstate, coreutils, e2fsprogs, gnutar, nix, systemd,
+            systemdconfig, templatedir, volumedir, zstd,
         },
         error::ResponseError,
-        fs::copy_dir_all,
-        string::between,
+        fs::{FileSnapshot, copy_dir_all, private_temp_dir, with_build_dir},
+        lock::KeyedLocks,
+        mode::ChangeMode,
+        output::Output,
+        profile::{self, current_generation, list_generations},
+        scope::ContainerId,
+        stream::{receive_payload, stream_command},
     },
 };
 
 use super::models::ContainerConfiguration;
 
+// Operations that stop, replace or remove a container wait for each other, e.g. a scheduled backup during a set
+pub static CONTAINER_LOCKS: KeyedLocks<String> = KeyedLocks::new();
+
 #[get("/containers")]
 async fn containers() -> impl Responder {
     let path = containersettings();
     match read_dir(&path) {
         Ok(dir) => {
+            // Skips in-progress restore dirs and other entries that are not containers
             let response: Vec<String> = dir
                 .filter_map(|f| f.ok().and_then(|f| f.file_name().into_string().ok()))
+                .filter(|name| ContainerId::try_from(name.clone()).is_ok())
                 .collect();
             HttpResponse::Ok().json(response)
         }
@@ -45,183 +74,55 @@ async fn containers() -> impl Responder {
 }
 
 #[get("/container/{container}/get")]
-async fn get(path: web::Path<String>) -> impl Responder {
+async fn get(path: web::Path<ContainerId>) -> impl Responder {
     let container_id = path.into_inner();
-    let path = containersettings().join(&container_id);
-
-    let flake: String;
-    let mut flake_lock: Option<String> = None;
-    let mut network: Option<String> = None;
-    let mut nvidia_gpus: Option<Vec<u64>> = None;
-
-    {
-        let path = path.join("flake.nix");
-        match read_to_string(&path) {
-            Ok(file) => {
-                flake = file;
-            }
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(ResponseError::new(format!(
-                    "Could not read container flake config {}: {}",
-                    path.display(),
-                    e
-                )));
-            }
-        }
-    }
-    {
-        let path = path.join("flake.lock");
-        match read_to_string(&path) {
-            Ok(file) => {
-                flake_lock = Some(file);
-            }
-            Err(e) => {
-                log::warn!(
-                    "Could not read container flake lock {}: {}",
-                    path.display(),
-                    e
-                );
-            }
-        }
-    }
-    {
-        let path = containerconfig().join(format!("{}.conf", &container_id));
-        match read_to_string(&path) {
-            Ok(file) => {
-                if let Some(nspawn_flags) = between(&file, "\"", "\"") {
-                    if nspawn_flags.contains("nvidia") {
-                        nvidia_gpus = Some(vec![]);
-                    }
-
-                    nspawn_flags.split(" ").for_each(|flag| {
-                        if flag.starts_with("--network-zone=") {
-                            network = Some(flag.replace("--network-zone=", ""));
-                        }
-
-                        if flag.starts_with("--bind-ro=") {
-                            let path = flag.replace("--bind-ro=", "");
-                            if path.starts_with("/dev/nvidia") {
-                                let device = path.replace("/dev/nvidia", "");
-                                if !["ctl", "-modeset", "-uvm", "-uvm-tools"]
-                                    .contains(&device.as_str())
-                                {
-                                    match device.parse::<u64>() {
-                                        Ok(device_id) => {
-                                            nvidia_gpus.get_or_insert(vec![]).push(device_id);
-                                        }
-                                        Err(e) => {
-                                            log::warn!(
-                                                "Could not parse nvidia device id {} to u64: {}",
-                                                device,
-                                                e
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    });
-                }
-            }
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(ResponseError::new(format!(
-                    "Could not read container config {}: {}",
-                    path.display(),
-                    e
-                )));
-            }
-        }
+    match get_configuration(&container_id) {
+        Ok(configuration) => HttpResponse::Ok().json(configuration),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(e)),
     }
-
-    HttpResponse::Ok().json(ContainerConfiguration {
-        flake,
-        flake_lock,
-        network,
-        nvidia_gpus,
-    })
 }
 
 #[post("/container/{container}/set")]
-async fn set(path: web::Path<String>, change: web::Json<ContainerChange>) -> impl Responder {
+async fn set(path: web::Path<ContainerId>, change: web::Json<ContainerChange>) -> impl Responder {
     return_request_id(Box::new(move |request_id| {
         let container_id = path.into_inner();
-        let path = containersettings().join(&container_id);
-        if let Err(e) = create_dir_all(&path) {
-            return RequestIdResult::Error {
-                error: format!("Error creating container folder {}: {}", path.display(), e),
-            };
-        }
-        log::info!("Created container dir {}", path.display());
-
-        {
-            let path = path.join("flake.nix");
-            if let Err(e) = write(&path, &change.settings.flake) {
-                return RequestIdResult::Error {
-                    error: format!(
-                        "Error writing container flake config {}: {}",
-                        path.display(),
-                        e
-                    ),
-                };
-            }
-            log::info!("Created container flake {}", path.display());
-        }
-
-        if let Some(update_inputs) = &change.update_inputs {
-            let mut command = Command::new(format!("{}nix", nix()));
-            command
-                .env("NIX_REMOTE", "daemon")
-                .arg("flake")
-                .arg("update");
-            for input in update_inputs {
-                command.arg(input);
-            }
-            command.arg("--flake").arg(&path);
+        let _lock = CONTAINER_LOCKS.lock(container_id.to_string());
+        set_container(&container_id, &change, request_id)
+    }))
+}
 
-            if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
-                return RequestIdResult::Error {
-                    error: format!(
-                        "Error flake updating nixos container {}: {}",
-                        container_id, e
-                    ),
-                };
+#[post("/container/{container}/diff")]
+async fn diff(path: web::Path<ContainerId>, change: web::Json<ContainerChange>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let container_id = path.into_inner();
+        with_build_dir(&containersettings().join(&container_id), |build_dir| {
+            if let Some(e) = write_flake(&container_id, &change, build_dir, request_id) {
+                return e;
             }
-        }
-
-        if let Some(e) = create_conf_file(
-            &container_id,
-            &change.settings.network,
-            &change.settings.nvidia_gpus,
-            request_id,
-        ) {
-            return e;
-        }
-        if let Some(e) = create_state_dir(&container_id) {
-            return e;
-        }
-        if let Some(e) = create_profile(path, &container_id, request_id) {
-            return e;
-        }
-
-        let mut command = Command::new(format!("{}systemctl", systemd()));
-        command
-            .arg("reload-or-restart")
-            .arg(format!("container@{}", container_id));
-
-        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
-            return RequestIdResult::Error {
-                error: format!("Error creating nixos container {}: {}", container_id, e),
+            let toplevel = match build_toplevel(build_dir, &build_dir.join("result"), request_id) {
+                Ok(toplevel) => toplevel,
+                Err(e) => return e,
             };
-        }
 
-        RequestIdResult::Success { body: None }
+            let current = containerprofile().join(&container_id).join("system");
+            match closure_diff(
+                current.exists().then_some(current.as_path()),
+                Path::new(&toplevel),
+            ) {
+                Ok(diff) => RequestIdResult::Success {
+                    body: Some(json!(diff).to_string()),
+                },
+                Err(error) => RequestIdResult::Error { error },
+            }
+        })
     }))
 }
 
 #[post("/container/{container}/remove")]
-async fn remove(path: web::Path<String>) -> impl Responder {
+async fn remove(path: web::Path<ContainerId>) -> impl Responder {
     return_request_id(Box::new(move |request_id| {
         let container_id = path.into_inner();
+        let _lock = CONTAINER_LOCKS.lock(container_id.to_string());
         let mut command = Command::new(format!("{}systemctl", systemd()));
         command
             .arg("stop")
@@ -264,136 +165,2383 @@ async fn remove(path: web::Path<String>) -> impl Responder {
     }))
 }
 
-fn create_profile(
-    flake: PathBuf,
-    container_id: &str,
-    request_id: RequestId,
-) -> Option<RequestIdResult> {
-    let container_profile = containerprofile().join(container_id);
-    log::info!("Creating profile {}", container_profile.display());
+#[post("/container/{container}/start")]
+async fn start(path: web::Path<ContainerId>) -> impl Responder {
+    container_command(path.into_inner(), "start")
+}
 
-    if let Err(e) = create_dir_all(&container_profile) {
-        return Some(RequestIdResult::Error {
-            error: format!(
-                "Error creating nixos profile {}: {}",
-                container_profile.display(),
-                e
-            ),
-        });
-    }
+#[post("/container/{container}/stop")]
+async fn stop(path: web::Path<ContainerId>) -> impl Responder {
+    container_command(path.into_inner(), "stop")
+}
 
-    let mut cli_command = Command::new(format!("{}nix", nix()));
-    cli_command
-        .env("NIX_REMOTE", "daemon")
-        .env("NIX_BUILD_CORES", buildcores().to_string())
-        .arg("build")
-        .arg("--profile")
-        .arg(container_profile.join("system"))
-        .arg(format!(
-            "{}#nixosConfigurations.container.config.system.build.toplevel",
-            flake.to_string_lossy()
-        ));
+#[post("/container/{container}/restart")]
+async fn restart(path: web::Path<ContainerId>) -> impl Responder {
+    container_command(path.into_inner(), "restart")
+}
 
-    if let Err(e) = execute_command(cli_command, CommandExecutionMode::Stream { request_id }) {
-        return Some(RequestIdResult::Error {
-            error: format!("Error building configuration {}: {}", flake.display(), e),
+#[get("/container/{container}/status")]
+async fn status(path: web::Path<ContainerId>) -> impl Responder {
+    let container_id = path.into_inner();
+
+    let mut command = Command::new(format!("{}systemctl", systemd()));
+    command
+        .arg("show")
+        .arg(format!("container@{}", container_id))
+        .arg("--property=ActiveState,MainPID,ActiveEnterTimestamp")
+        .arg("--timestamp=unix");
+    let properties = match execute_command(command, CommandExecutionMode::Simple) {
+        Ok(output) => match output.into() {
+            Output::UTF8 { output } => parse_properties(&output),
+            Output::Bytes { output } => {
+                return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                    "Container {} status could not be decoded as UTF8: {:?}.",
+                    container_id, output
+                )));
+            }
+        },
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error getting status of container {}: {}",
+                container_id, e
+            )));
+        }
+    };
+
+    if properties.get("ActiveState").map(|s| s.as_str()) != Some("active") {
+        return HttpResponse::Ok().json(ContainerStatus {
+            running: false,
+            uptime: None,
+            main_pid: None,
+            ip_addresses: vec![],
         });
     }
 
-    None
-}
-fn remove_profile(container_id: &str) -> Option<RequestIdResult> {
-    let container_profile = containerprofile().join(container_id);
-    if let Err(e) = remove_dir_all(&container_profile) {
-        return Some(RequestIdResult::Error {
-            error: format!(
-                "Error deleting nixos profile {}: {}",
-                container_profile.display(),
-                e
-            ),
+    let uptime = properties
+        .get("ActiveEnterTimestamp")
+        .and_then(|timestamp| timestamp.trim_start_matches('@').parse::<u64>().ok())
+        .and_then(|started| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|now| now.as_secs().saturating_sub(started))
         });
+    let main_pid = properties
+        .get("MainPID")
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .filter(|pid| *pid != 0);
+
+    HttpResponse::Ok().json(ContainerStatus {
+        running: true,
+        uptime,
+        main_pid,
+        ip_addresses: get_ip_addresses(&container_id),
+    })
+}
+
+#[get("/container/{container}/generations")]
+async fn generations(path: web::Path<ContainerId>) -> impl Responder {
+    let container_id = path.into_inner();
+    let path = containerprofile().join(&container_id);
+
+    match list_generations(&path.join("system")) {
+        Ok(generations) => {
+            let response: Vec<ContainerGeneration> = generations
+                .into_iter()
+                .filter_map(|generation| {
+                    let store_path = read_link(&generation.path)
+                        .ok()?
+                        .to_string_lossy()
+                        .to_string();
+                    Some(ContainerGeneration {
+                        number: generation.number,
+                        created: generation.created,
+                        store_path,
+                        current: generation.current,
+                    })
+                })
+                .collect();
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Could not read container profile dir {}: {}",
+            path.display(),
+            e
+        ))),
     }
+}
 
-    None
+#[post("/container/{container}/rollback")]
+async fn rollback(path: web::Path<ContainerId>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let container_id = path.into_inner();
+        let _lock = CONTAINER_LOCKS.lock(container_id.to_string());
+        switch_profile(&container_id, None, request_id)
+    }))
 }
 
-fn create_state_dir(container_id: &str) -> Option<RequestIdResult> {
-    let state_dir = containerstate().join(container_id);
-    log::info!("Creating state dir {}", state_dir.display());
+#[post("/container/{container}/switch-generation/{generation}")]
+async fn switch_generation(path: web::Path<(ContainerId, u64)>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let (container_id, generation) = path.into_inner();
+        let _lock = CONTAINER_LOCKS.lock(container_id.to_string());
+        switch_profile(&container_id, Some(generation), request_id)
+    }))
+}
 
-    if let Err(e) = create_dir_all(&state_dir) {
-        return Some(RequestIdResult::Error {
-            error: format!(
-                "Error creating nixos container state directory {}: {}",
-                state_dir.display(),
+#[get("/volumes")]
+async fn volumes() -> impl Responder {
+    let path = volumedir();
+    let names: Vec<String> = match read_dir(&path) {
+        Ok(dir) => dir
+            .filter_map(|f| f.ok().and_then(|f| f.file_name().into_string().ok()))
+            .collect(),
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Could not read volume dir {}: {}",
+                path.display(),
                 e
-            ),
-        });
+            )));
+        }
+    };
+    let nspawn_flags = match all_nspawn_flags() {
+        Ok(nspawn_flags) => nspawn_flags,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(e));
+        }
+    };
+
+    let response: Vec<Volume> = names
+        .into_iter()
+        .map(|name| Volume {
+            used_by: volume_users(&name, &nspawn_flags),
+            name,
+        })
+        .collect();
+    HttpResponse::Ok().json(response)
+}
+
+#[post("/volume/{volume}/create")]
+async fn create_volume(path: web::Path<String>) -> impl Responder {
+    let name = path.into_inner();
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest()
+            .json(ResponseError::new(format!("Invalid volume name {}", name)));
     }
 
-    // Create xnode-config in container state
-    let xnode_config_in_dir = state_dir.join("xnode-config");
-    if let Err(e) = create_dir_all(&xnode_config_in_dir) {
-        return Some(RequestIdResult::Error {
-            error: format!(
-                "Error creating container state xnode-config directory {}: {}",
-                xnode_config_in_dir.display(),
-                e
-            ),
-        });
+    let path = volumedir().join(&name);
+    match create_dir_all(&path) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error creating volume {}: {}",
+            path.display(),
+            e
+        ))),
     }
+}
 
-    // Create xnode-config in container config
-    let xnode_config_out_dir = containersettings().join(container_id).join("xnode-config");
-    if let Err(e) = create_dir_all(&xnode_config_out_dir) {
-        return Some(RequestIdResult::Error {
-            error: format!(
-                "Error creating container config xnode-config directory {}: {}",
-                xnode_config_out_dir.display(),
-                e
-            ),
-        });
+#[post("/volume/{volume}/remove")]
+async fn remove_volume(path: web::Path<String>) -> impl Responder {
+    let name = path.into_inner();
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest()
+            .json(ResponseError::new(format!("Invalid volume name {}", name)));
     }
 
-    // Set container host platform to same as host
-    let mut get_host_platform = Command::new("uname");
-    get_host_platform.arg("-m");
-    match execute_command(get_host_platform, CommandExecutionMode::Simple) {
-        Ok(mut bytes) => {
-            let path = xnode_config_out_dir.join("host-platform");
-            let mut postfix: Vec<u8> = "-linux".into();
-            bytes.pop(); // remove newline
-            bytes.append(&mut postfix);
-            if let Err(e) = write(&path, bytes) {
-                return Some(RequestIdResult::Error {
-                    error: format!("Error writing host platform to {}: {}", path.display(), e),
-                });
-            }
-        }
+    let used_by = match all_nspawn_flags() {
+        Ok(nspawn_flags) => volume_users(&name, &nspawn_flags),
         Err(e) => {
-            return Some(RequestIdResult::Error {
-                error: format!("Error getting host platform: {}", e),
-            });
+            return HttpResponse::InternalServerError().json(ResponseError::new(e));
         }
+    };
+    if !used_by.is_empty() {
+        return HttpResponse::BadRequest().json(ResponseError::new(format!(
+            "Volume {} is still used by containers {}",
+            name,
+            used_by.join(", ")
+        )));
     }
 
-    // Set container hostname to it's container id
-    {
-        let path = xnode_config_out_dir.join("hostname");
-        if let Err(e) = write(&path, container_id) {
-            return Some(RequestIdResult::Error {
-                error: format!("Error writing hostname to {}: {}", path.display(), e),
-            });
-        }
+    let path = volumedir().join(&name);
+    match remove_dir_all(&path) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error removing volume {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+#[get("/container/{container}/backups")]
+async fn backups(path: web::Path<ContainerId>) -> impl Responder {
+    let container_id = path.into_inner();
+    match get_backups(&container_id) {
+        Ok(backups) => HttpResponse::Ok().json(backups),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(e)),
+    }
+}
+
+#[post("/container/{container}/backup")]
+async fn backup(path: web::Path<ContainerId>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let container_id = path.into_inner();
+        let _lock = CONTAINER_LOCKS.lock(container_id.to_string());
+        match create_backup(&container_id, request_id) {
+            Ok(backup_id) => RequestIdResult::Success {
+                body: Some(backup_id),
+            },
+            Err(e) => e,
+        }
+    }))
+}
+
+#[post("/container/{container}/restore/{backup_id}")]
+async fn restore(path: web::Path<(ContainerId, String)>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let (container_id, backup_id) = path.into_inner();
+        let _lock = CONTAINER_LOCKS.lock(container_id.to_string());
+        let backup_dir = match get_backup_dir(&container_id, &backup_id) {
+            Ok(backup_dir) => backup_dir,
+            Err(error) => return RequestIdResult::Error { error },
+        };
+
+        // Extracted next to the live dirs (same filesystem) and swapped in only once both succeeded
+        let dirs = [
+            ("settings.tar.zst", containersettings()),
+            ("state.tar.zst", containerstate()),
+        ]
+        .map(|(archive, parent)| RestoreDirs {
+            archive: backup_dir.join(archive),
+            live: parent.join(&container_id),
+            new: parent.join(format!(".{}-restore", container_id)),
+            old: parent.join(format!(".{}-old", container_id)),
+        });
+        for dir in &dirs {
+            for leftover in [&dir.new, &dir.old] {
+                if leftover.exists()
+                    && let Some(e) = remove_immutable_dir(leftover, request_id)
+                {
+                    return e;
+                }
+            }
+        }
+        for dir in &dirs {
+            if let Some(e) = extract_archive(&dir.archive, &dir.new, request_id) {
+                for dir in &dirs {
+                    let _ = remove_immutable_dir(&dir.new, request_id);
+                }
+                return e;
+            }
+        }
+
+        let running = is_container_running(&container_id);
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg("stop")
+            .arg(format!("container@{}", container_id));
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            for dir in &dirs {
+                let _ = remove_immutable_dir(&dir.new, request_id);
+            }
+            return RequestIdResult::Error {
+                error: format!("Error stopping nixos container {}: {}", container_id, e),
+            };
+        }
+
+        let mut swapped: Vec<&RestoreDirs> = vec![];
+        let mut error = None;
+        for dir in &dirs {
+            match swap_in_restore(dir) {
+                Ok(()) => swapped.push(dir),
+                Err(e) => {
+                    error = Some(RequestIdResult::Error { error: e });
+                    break;
+                }
+            }
+        }
+        if error.is_none() {
+            error = create_profile(dirs[0].live.clone(), &container_id, request_id);
+        }
+        if let Some(e) = error {
+            // Put the previous settings and state back
+            for dir in swapped.into_iter().rev() {
+                if let Err(e) = swap_out_restore(dir) {
+                    log::warn!("{}", e);
+                }
+            }
+            for dir in &dirs {
+                let _ = remove_immutable_dir(&dir.new, request_id);
+            }
+            if running {
+                let mut command = Command::new(format!("{}systemctl", systemd()));
+                command
+                    .arg("start")
+                    .arg(format!("container@{}", container_id));
+                let _ = execute_command(command, CommandExecutionMode::Stream { request_id });
+            }
+            return e;
+        }
+
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg("reload-or-restart")
+            .arg(format!("container@{}", container_id));
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!("Error restarting nixos container {}: {}", container_id, e),
+            };
+        }
+
+        for dir in &dirs {
+            if dir.old.exists()
+                && let Some(RequestIdResult::Error { error }) =
+                    remove_immutable_dir(&dir.old, request_id)
+            {
+                log::warn!("{}", error);
+            }
+        }
+
+        RequestIdResult::Success { body: None }
+    }))
+}
+
+#[post("/container/{container}/backup/{backup_id}/remove")]
+async fn remove_backup(path: web::Path<(ContainerId, String)>) -> impl Responder {
+    let (container_id, backup_id) = path.into_inner();
+    let backup_dir = match get_backup_dir(&container_id, &backup_id) {
+        Ok(backup_dir) => backup_dir,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(ResponseError::new(error));
+        }
+    };
+
+    match remove_dir_all(&backup_dir) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error deleting container backup {}: {}",
+            backup_dir.display(),
+            e
+        ))),
+    }
+}
+
+#[post("/container/{container}/clone")]
+async fn clone(path: web::Path<ContainerId>, options: web::Json<ContainerClone>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let container_id = path.into_inner();
+        let ContainerClone {
+            container: target,
+            state,
+            ports,
+        } = options.into_inner();
+        if container_exists(&target) {
+            return RequestIdResult::Error {
+                error: format!("Container {} already exists", target),
+            };
+        }
+
+        let configuration = match get_configuration(&container_id) {
+            Ok(configuration) => configuration,
+            Err(error) => return RequestIdResult::Error { error },
+        };
+
+        // Settings dir includes flake.lock, so the clone is built from the same inputs
+        let source_dir = containersettings().join(&container_id);
+        let target_dir = containersettings().join(&target);
+        if let Err(e) = copy_dir_all(&source_dir, &target_dir) {
+            let _ = remove_dir_all(&target_dir);
+            return RequestIdResult::Error {
+                error: format!(
+                    "Error copying container settings {} to {}: {}",
+                    source_dir.display(),
+                    target_dir.display(),
+                    e
+                ),
+            };
+        }
+
+        if state && let Some(e) = clone_state_dir(&container_id, &target, request_id) {
+            let _ = remove_container_dirs(&target, request_id);
+            return e;
+        }
+
+        let change = ContainerChange {
+            settings: ContainerSettings {
+                flake: configuration.flake,
+                network: configuration.network,
+                nvidia_gpus: configuration.nvidia_gpus,
+                devices: configuration.devices,
+                ports,
+                mounts: configuration.mounts,
+                resource_limits: configuration.resource_limits,
+                backup_schedule: configuration.backup_schedule,
+            },
+            update_inputs: None,
+            mode: None,
+        };
+        create_container(&target, &change, request_id)
+    }))
+}
+
+#[post("/container/{container}/rename/{new_container}")]
+async fn rename(path: web::Path<(ContainerId, ContainerId)>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let (container_id, target) = path.into_inner();
+        // In a fixed order, so two renames between the same containers can not wait on each other
+        let mut ids = [container_id.to_string(), target.to_string()];
+        ids.sort();
+        let _locks = ids.map(|id| CONTAINER_LOCKS.lock(id));
+        if !containersettings().join(&container_id).exists() {
+            return RequestIdResult::Error {
+                error: format!("Container {} does not exist", container_id),
+            };
+        }
+        if container_exists(&target) {
+            return RequestIdResult::Error {
+                error: format!("Container {} already exists", target),
+            };
+        }
+
+        let running = is_container_running(&container_id);
+        if running {
+            let mut command = Command::new(format!("{}systemctl", systemd()));
+            command
+                .arg("stop")
+                .arg(format!("container@{}", container_id));
+            if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+                return RequestIdResult::Error {
+                    error: format!("Error stopping nixos container {}: {}", container_id, e),
+                };
+            }
+        }
+
+        let result = move_container(&container_id, &target, request_id);
+        if running {
+            // Start under the new id, or the old one if the rename was reverted
+            let started = if result.is_ok() {
+                &target
+            } else {
+                &container_id
+            };
+            let mut command = Command::new(format!("{}systemctl", systemd()));
+            command.arg("start").arg(format!("container@{}", started));
+            if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+                return RequestIdResult::Error {
+                    error: format!(
+                        "{}Error starting nixos container {}: {}",
+                        result.err().map(|e| format!("{}. ", e)).unwrap_or_default(),
+                        started,
+                        e
+                    ),
+                };
+            }
+        }
+
+        match result {
+            Ok(()) => RequestIdResult::Success { body: None },
+            Err(error) => RequestIdResult::Error { error },
+        }
+    }))
+}
+
+#[get("/container/{container}/export")]
+async fn export(path: web::Path<ContainerId>, query: web::Query<ExportQuery>) -> impl Responder {
+    let container_id = path.into_inner();
+    let state = query.state.unwrap_or(false);
+    let compress = query.compress.unwrap_or(false);
+
+    let configuration = match get_configuration(&container_id) {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(e));
+        }
+    };
+    let settings_dir = containersettings().join(&container_id);
+    let manifest = ContainerManifest {
+        container: container_id.clone(),
+        created: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        host_platform: read_to_string(settings_dir.join("xnode-config").join("host-platform"))
+            .ok()
+            .map(|platform| platform.trim().to_string())
+            .or_else(|| host_platform().ok()),
+        state,
+        settings: ContainerSettings {
+            flake: configuration.flake,
+            network: configuration.network,
+            nvidia_gpus: configuration.nvidia_gpus,
+            devices: configuration.devices,
+            ports: configuration.ports,
+            mounts: configuration.mounts,
+            resource_limits: configuration.resource_limits,
+            backup_schedule: configuration.backup_schedule,
+        },
+    };
+
+    // Manifest is written to a separate dir, to add it to the root of the archive
+    let manifest_dir = match private_temp_dir("export") {
+        Ok(manifest_dir) => manifest_dir,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error creating export manifest dir: {}",
+                e
+            )));
+        }
+    };
+    if let Err(e) = write(
+        manifest_dir.join("manifest.json"),
+        json!(manifest).to_string(),
+    ) {
+        let _ = remove_dir_all(&manifest_dir);
+        return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error writing export manifest {}: {}",
+            manifest_dir.display(),
+            e
+        )));
+    }
+
+    // Stop the container while archiving its state, otherwise files could change mid-export
+    let stop_container = container_id.clone();
+    let stopped = web::block(move || {
+        let running = state && is_container_running(&stop_container);
+        if running {
+            let mut command = Command::new(format!("{}systemctl", systemd()));
+            command
+                .arg("stop")
+                .arg(format!("container@{}", stop_container));
+            execute_command(command, CommandExecutionMode::Simple)
+                .map_err(|e| format!("Error stopping nixos container {}: {}", stop_container, e))?;
+        }
+        Ok::<bool, String>(running)
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result);
+    let running = match stopped {
+        Ok(running) => running,
+        Err(e) => {
+            let _ = remove_dir_all(&manifest_dir);
+            return HttpResponse::InternalServerError().json(ResponseError::new(e));
+        }
+    };
+
+    let mut command = Command::new(format!("{}tar", gnutar()));
+    command.arg("--create");
+    if compress {
+        command.arg(format!("--use-compress-program={}zstd", zstd()));
+    }
+    command
+        .arg("--file=-")
+        .arg("--acls")
+        .arg("--xattrs")
+        .arg("--numeric-owner")
+        .arg(tar_transform(&settings_dir, "settings"));
+    let state_dir = containerstate().join(&container_id);
+    if state {
+        command.arg(tar_transform(&state_dir, "state"));
+    }
+    command
+        .arg("--directory")
+        .arg(&manifest_dir)
+        .arg("manifest.json")
+        .arg("--directory")
+        .arg("/")
+        .arg(settings_dir.strip_prefix("/").unwrap_or(&settings_dir));
+    if state {
+        command.arg(state_dir.strip_prefix("/").unwrap_or(&state_dir));
+    }
+
+    let cleanup_dir = manifest_dir.clone();
+    let cleanup_container = container_id.clone();
+    let on_finish = Box::new(move || {
+        if let Err(e) = remove_dir_all(&cleanup_dir) {
+            log::warn!(
+                "Could not remove export manifest dir {}: {}",
+                cleanup_dir.display(),
+                e
+            );
+        }
+        if running {
+            let mut command = Command::new(format!("{}systemctl", systemd()));
+            command
+                .arg("start")
+                .arg(format!("container@{}", cleanup_container));
+            if let Err(e) = execute_command(command, CommandExecutionMode::Simple) {
+                log::warn!(
+                    "Could not start nixos container {} after export: {}",
+                    cleanup_container,
+                    e
+                );
+            }
+        }
+    });
+
+    match stream_command(command, on_finish) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(if compress {
+                "application/zstd"
+            } else {
+                "application/x-tar"
+            })
+            .insert_header((
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}.tar{}\"",
+                    container_id,
+                    if compress { ".zst" } else { "" }
+                ),
+            ))
+            .streaming(body),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error exporting container {}: {}",
+            container_id, e
+        ))),
+    }
+}
+
+#[post("/container/import")]
+async fn import(query: web::Query<ImportQuery>, payload: web::Payload) -> impl Responder {
+    let upload_dir = match private_temp_dir("import") {
+        Ok(upload_dir) => upload_dir,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error creating import dir: {}",
+                e
+            )));
+        }
+    };
+    let upload = upload_dir.join("archive");
+    if let Err((code, e)) = receive_payload(payload, &upload, archivelimit()).await {
+        let _ = remove_dir_all(&upload_dir);
+        return HttpResponse::build(code).json(ResponseError::new(format!(
+            "Error receiving import archive: {}",
+            e
+        )));
+    }
+
+    return_request_id(Box::new(move |request_id| {
+        let result = import_container(&upload, query.into_inner().container, request_id);
+        if let Err(e) = remove_dir_all(&upload_dir) {
+            log::warn!(
+                "Could not remove import dir {}: {}",
+                upload_dir.display(),
+                e
+            );
+        }
+
+        result
+    }))
+}
+
+#[get("/templates")]
+async fn templates() -> impl Responder {
+    let dir = templatedir();
+    let entries = match read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error reading template dir {}: {}",
+                dir.display(),
+                e
+            )));
+        }
+    };
+
+    let mut container_templates: Vec<ContainerTemplate> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_suffix(".json")
+                .map(|name| name.to_string())
+        })
+        .filter_map(|name| match read_template(&name) {
+            Ok(template) => Some(template),
+            Err(e) => {
+                log::warn!("{}", e);
+                None
+            }
+        })
+        .collect();
+    container_templates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    HttpResponse::Ok().json(container_templates)
+}
+
+#[get("/template/{template}/get")]
+async fn get_template(path: web::Path<String>) -> impl Responder {
+    let name = path.into_inner();
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest().json(ResponseError::new(format!(
+            "Invalid template name {}",
+            name
+        )));
+    }
+
+    match read_template(&name) {
+        Ok(template) => HttpResponse::Ok().json(template),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(e)),
+    }
+}
+
+#[post("/template/{template}/set")]
+async fn set_template(
+    path: web::Path<String>,
+    settings: web::Json<ContainerSettings>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest().json(ResponseError::new(format!(
+            "Invalid template name {}",
+            name
+        )));
+    }
+
+    let path = template_file(&name);
+    match write(&path, json!(settings.into_inner()).to_string()) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error writing template {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+#[post("/template/{template}/remove")]
+async fn remove_template(path: web::Path<String>) -> impl Responder {
+    let name = path.into_inner();
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest().json(ResponseError::new(format!(
+            "Invalid template name {}",
+            name
+        )));
+    }
+
+    let path = template_file(&name);
+    match remove_file(&path) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error deleting template {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+#[post("/template/{template}/instantiate")]
+async fn instantiate_template(
+    path: web::Path<String>,
+    instance: web::Json<TemplateInstance>,
+) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let name = path.into_inner();
+        let TemplateInstance {
+            container: container_id,
+            mut parameters,
+        } = instance.into_inner();
+        if !valid_name(&name) {
+            return RequestIdResult::Error {
+                error: format!("Invalid template name {}", name),
+            };
+        }
+        if container_exists(&container_id) {
+            return RequestIdResult::Error {
+                error: format!("Container {} already exists", container_id),
+            };
+        }
+
+        let template = match read_template(&name) {
+            Ok(template) => template,
+            Err(error) => return RequestIdResult::Error { error },
+        };
+        parameters.insert("container".to_string(), container_id.to_string());
+        let mut missing = vec![];
+        let settings = map_strings(json!(template.settings), &mut |text| {
+            replace_parameters(&text, &parameters, &mut missing)
+        });
+        if !missing.is_empty() {
+            return RequestIdResult::Error {
+                error: format!("Missing template parameters {}", missing.join(", ")),
+            };
+        }
+        let settings = match serde_json::from_value::<ContainerSettings>(settings) {
+            Ok(settings) => settings,
+            Err(e) => {
+                return RequestIdResult::Error {
+                    error: format!("Error instantiating template {}: {}", name, e),
+                };
+            }
+        };
+
+        let change = ContainerChange {
+            settings,
+            update_inputs: None,
+            mode: None,
+        };
+        create_container(&container_id, &change, request_id)
+    }))
+}
+
+pub fn get_configuration(container_id: &str) -> Result<ContainerConfiguration, String> {
+    let path = containersettings().join(container_id);
+
+    let flake: String;
+    let mut flake_lock: Option<String> = None;
+    let mut network: Option<String> = None;
+    let mut nvidia_gpus: Option<Vec<u64>> = None;
+    let mut devices: Option<Vec<Device>> = None;
+    let mut ports: Option<Vec<PortForward>> = None;
+    let mut mounts: Option<Vec<Mount>> = None;
+    let mut resource_limits: Option<ResourceLimits> = None;
+    let mut backup_schedule: Option<BackupSchedule> = None;
+
+    {
+        let path = path.join("flake.nix");
+        match read_to_string(&path) {
+            Ok(file) => {
+                flake = file;
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Could not read container flake config {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+        }
+    }
+    {
+        let path = path.join("flake.lock");
+        match read_to_string(&path) {
+            Ok(file) => {
+                flake_lock = Some(file);
+            }
+            Err(e) => {
+                log::warn!(
+                    "Could not read container flake lock {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+    {
+        let path = conf_file(container_id);
+        match ContainerConf::read(&path) {
+            Ok(conf) => {
+                let nspawn_flags = conf.extra_nspawn_flags;
+                // Only the read-only bundle written for nvidia_gpus, devices (e.g. /dev/nvidia0) are bound writable
+                if nspawn_flags
+                    .iter()
+                    .any(|flag| flag == "--bind-ro=/dev/nvidiactl")
+                {
+                    nvidia_gpus = Some(vec![]);
+                }
+
+                nspawn_flags.iter().for_each(|flag| {
+                    if flag.starts_with("--network-zone=") {
+                        network = Some(flag.replace("--network-zone=", ""));
+                    }
+
+                    if let Some(port) = parse_port(flag) {
+                        ports.get_or_insert(vec![]).push(port);
+                    }
+
+                    if let Some(mount) = parse_mount(flag) {
+                        if !mount.container_path.starts_with("/dev/") {
+                            mounts.get_or_insert(vec![]).push(mount);
+                        } else if !mount.read_only
+                            && let MountSource::Host(path) = mount.source
+                        {
+                            devices.get_or_insert(vec![]).push(parse_device(&path));
+                        }
+                    }
+
+                    if flag.starts_with("--bind-ro=") {
+                        let path = flag.replace("--bind-ro=", "");
+                        if path.starts_with("/dev/nvidia") {
+                            let device = path.replace("/dev/nvidia", "");
+                            if !["ctl", "-modeset", "-uvm", "-uvm-tools"].contains(&device.as_str())
+                            {
+                                match device.parse::<u64>() {
+                                    Ok(device_id) => {
+                                        nvidia_gpus.get_or_insert(vec![]).push(device_id);
+                                    }
+                                    Err(e) => {
+                                        log::warn!(
+                                            "Could not parse nvidia device id {} to u64: {}",
+                                            device,
+                                            e
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Could not read container config {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+        }
+    }
+
+    {
+        let path = systemd_conf_file(container_id);
+        match read_to_string(&path) {
+            Ok(file) => {
+                let mut limits = ResourceLimits::default();
+                for (key, value) in file.lines().filter_map(|line| line.split_once("=")) {
+                    match key {
+                        "CPUQuota" => limits.cpu_quota = value.trim_end_matches('%').parse().ok(),
+                        "CPUWeight" => limits.cpu_weight = value.parse().ok(),
+                        "MemoryMax" => limits.memory_max = value.parse().ok(),
+                        "MemoryHigh" => limits.memory_high = value.parse().ok(),
+                        "IOWeight" => limits.io_weight = value.parse().ok(),
+                        "TasksMax" => limits.tasks_max = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+                if limits != ResourceLimits::default() {
+                    resource_limits = Some(limits);
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    "Could not read container systemd config {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    match get_backup_schedule(container_id) {
+        Ok(schedule) => {
+            backup_schedule = schedule;
+        }
+        Err(e) => {
+            log::warn!("{}", e);
+        }
+    }
+
+    Ok(ContainerConfiguration {
+        flake,
+        flake_lock,
+        network,
+        nvidia_gpus,
+        devices,
+        ports,
+        mounts,
+        resource_limits,
+        backup_schedule,
+    })
+}
+
+fn set_container(
+    container_id: &str,
+    change: &ContainerChange,
+    request_id: RequestId,
+) -> RequestIdResult {
+    let mode = change.mode.unwrap_or(ChangeMode::Switch);
+    let path = containersettings().join(container_id);
+
+    if mode == ChangeMode::Build {
+        return with_build_dir(&containersettings().join(container_id), |build_dir| {
+            if let Some(e) = write_flake(container_id, change, build_dir, request_id) {
+                return e;
+            }
+
+            match build_toplevel(build_dir, &build_dir.join("result"), request_id) {
+                Ok(toplevel) => RequestIdResult::Success {
+                    body: Some(toplevel),
+                },
+                Err(e) => e,
+            }
+        });
+    }
+    if mode == ChangeMode::Test {
+        return test_change(container_id, change, request_id);
+    }
+
+    // A new container is removed completely if the change fails
+    let created = !path.exists();
+    if let Err(e) = create_dir_all(&path) {
+        return RequestIdResult::Error {
+            error: format!("Error creating container folder {}: {}", path.display(), e),
+        };
+    }
+    log::info!("Created container dir {}", path.display());
+
+    // Everything needed to put the container back in its current state if the change fails
+    let snapshot = match FileSnapshot::new(
+        ["flake.nix", "flake.lock", "backup-schedule.json"]
+            .map(|name| path.join(name))
+            .into_iter()
+            .chain([conf_file(container_id), systemd_conf_file(container_id)]),
+    ) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return RequestIdResult::Error {
+                error: format!(
+                    "Error reading current container settings {}: {}",
+                    path.display(),
+                    e
+                ),
+            };
+        }
+    };
+    let previous_profile = read_link(containerprofile().join(container_id).join("system")).ok();
+    let running = is_container_running(container_id);
+
+    let result = apply_change(container_id, change, mode, &path, request_id);
+    if let RequestIdResult::Error { error } = &result {
+        return RequestIdResult::Error {
+            error: match revert_change(
+                container_id,
+                &snapshot,
+                &previous_profile,
+                running,
+                created,
+                request_id,
+            ) {
+                Ok(()) => format!(
+                    "{}. Reverted container {} to its previous configuration",
+                    error, container_id
+                ),
+                Err(e) => format!(
+                    "{}. Reverting container {} failed: {}",
+                    error, container_id, e
+                ),
+            },
+        };
+    }
+
+    result
+}
+
+fn apply_change(
+    container_id: &str,
+    change: &ContainerChange,
+    mode: ChangeMode,
+    path: &Path,
+    request_id: RequestId,
+) -> RequestIdResult {
+    if let Some(e) = write_flake(container_id, change, path, request_id) {
+        return e;
+    }
+
+    {
+        let path = path.join("backup-schedule.json");
+        let result = match &change.settings.backup_schedule {
+            Some(schedule) => write(&path, json!(schedule).to_string()),
+            None if path.exists() => remove_file(&path),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            return RequestIdResult::Error {
+                error: format!(
+                    "Error updating container backup schedule {}: {}",
+                    path.display(),
+                    e
+                ),
+            };
+        }
+    }
+
+    if let Some(e) = create_conf_file(container_id, &change.settings, request_id) {
+        return e;
+    }
+    if let Some(e) = create_state_dir(container_id) {
+        return e;
+    }
+
+    if let Some(e) = create_profile(path.to_path_buf(), container_id, request_id) {
+        return e;
+    }
+
+    if mode == ChangeMode::Switch {
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg("reload-or-restart")
+            .arg(format!("container@{}", container_id));
+
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!("Error creating nixos container {}: {}", container_id, e),
+            };
+        }
+    }
+
+    RequestIdResult::Success { body: None }
+}
+
+// Activate inside the running container, without adding a profile generation
+// Built in a copy of the settings, so a restart goes back to the current configuration
+// Network, ports, mounts and other nspawn settings only apply on restart, so they are not tested
+fn test_change(
+    container_id: &str,
+    change: &ContainerChange,
+    request_id: RequestId,
+) -> RequestIdResult {
+    if !is_container_running(container_id) {
+        return RequestIdResult::Error {
+            error: format!(
+                "Container {} needs to be running to test a configuration",
+                container_id
+            ),
+        };
+    }
+
+    with_build_dir(&containersettings().join(container_id), |build_dir| {
+        if let Some(e) = write_flake(container_id, change, build_dir, request_id) {
+            return e;
+        }
+
+        // Out link in the profile dir keeps the tested configuration from being garbage collected
+        let out_link = containerprofile().join(container_id).join("test");
+        let toplevel = match build_toplevel(build_dir, &out_link, request_id) {
+            Ok(toplevel) => toplevel,
+            Err(e) => return e,
+        };
+        let mut command = Command::new(format!("{}systemd-run", systemd()));
+        command
+            .arg(format!("--machine={}", container_id))
+            .arg("--wait")
+            .arg("--pipe")
+            .arg("--quiet")
+            .arg(
+                Path::new(&toplevel)
+                    .join("bin")
+                    .join("switch-to-configuration"),
+            )
+            .arg("test");
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!(
+                    "Error testing configuration in nixos container {}: {}",
+                    container_id, e
+                ),
+            };
+        }
+
+        RequestIdResult::Success { body: None }
+    })
+}
+
+fn revert_change(
+    container_id: &str,
+    snapshot: &FileSnapshot,
+    previous_profile: &Option<PathBuf>,
+    running: bool,
+    created: bool,
+    request_id: RequestId,
+) -> Result<(), String> {
+    log::info!("Reverting failed change of container {}", container_id);
+
+    snapshot
+        .restore()
+        .map_err(|e| format!("Error restoring previous container settings: {}", e))?;
+
+    let mut command = Command::new(format!("{}systemctl", systemd()));
+    command.arg("daemon-reload");
+    execute_command(command, CommandExecutionMode::Stream { request_id })
+        .map_err(|e| format!("Error reloading systemd daemon: {}", e))?;
+
+    let container_profile = containerprofile().join(container_id);
+    if read_link(container_profile.join("system")).ok() != *previous_profile {
+        match previous_profile {
+            Some(previous_profile) => {
+                // Profile links point to their generation link, e.g. system-3-link
+                let generation = previous_profile
+                    .to_str()
+                    .and_then(|link| link.strip_prefix("system-"))
+                    .and_then(|link| link.strip_suffix("-link"))
+                    .ok_or(format!(
+                        "Unknown previous profile generation {}",
+                        previous_profile.display()
+                    ))?;
+                let mut command = Command::new(format!("{}nix-env", nix()));
+                command
+                    .env("NIX_REMOTE", "daemon")
+                    .arg("--profile")
+                    .arg(container_profile.join("system"))
+                    .arg("--switch-generation")
+                    .arg(generation);
+                execute_command(command, CommandExecutionMode::Stream { request_id })
+                    .map_err(|e| format!("Error switching back to previous profile: {}", e))?;
+            }
+            None => {
+                if let Some(RequestIdResult::Error { error }) = remove_profile(container_id) {
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    let mut command = Command::new(format!("{}systemctl", systemd()));
+    command
+        .arg(if running { "restart" } else { "stop" })
+        .arg(format!("container@{}", container_id));
+    execute_command(command, CommandExecutionMode::Stream { request_id })
+        .map_err(|e| format!("Error restarting container on previous profile: {}", e))?;
+
+    // Settings dir, xnode-config and state dir of a container that did not exist before
+    if created
+        && let Some(RequestIdResult::Error { error }) =
+            remove_container_dirs(container_id, request_id)
+    {
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+fn write_flake(
+    container_id: &str,
+    change: &ContainerChange,
+    path: &Path,
+    request_id: RequestId,
+) -> Option<RequestIdResult> {
+    {
+        let path = path.join("flake.nix");
+        if let Err(e) = write(&path, &change.settings.flake) {
+            return Some(RequestIdResult::Error {
+                error: format!(
+                    "Error writing container flake config {}: {}",
+                    path.display(),
+                    e
+                ),
+            });
+        }
+        log::info!("Created container flake {}", path.display());
+    }
+
+    if let Some(update_inputs) = &change.update_inputs {
+        let mut command = Command::new(format!("{}nix", nix()));
+        command
+            .env("NIX_REMOTE", "daemon")
+            .arg("flake")
+            .arg("update");
+        for input in update_inputs {
+            command.arg(input);
+        }
+        command.arg("--flake").arg(path);
+
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return Some(RequestIdResult::Error {
+                error: format!(
+                    "Error flake updating nixos container {}: {}",
+                    container_id, e
+                ),
+            });
+        }
+    }
+
+    None
+}
+
+fn build_toplevel(
+    flake: &Path,
+    out_link: &Path,
+    request_id: RequestId,
+) -> Result<String, RequestIdResult> {
+    let mut command = Command::new(format!("{}nix", nix()));
+    command
+        .env("NIX_REMOTE", "daemon")
+        .env("NIX_BUILD_CORES", buildcores().to_string())
+        .arg("build")
+        .arg("--out-link")
+        .arg(out_link)
+        .arg(format!(
+            "{}#nixosConfigurations.container.config.system.build.toplevel",
+            flake.to_string_lossy()
+        ));
+
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+        return Err(RequestIdResult::Error {
+            error: format!("Error building configuration {}: {}", flake.display(), e),
+        });
+    }
+
+    read_link(out_link)
+        .map(|toplevel| toplevel.to_string_lossy().to_string())
+        .map_err(|e| RequestIdResult::Error {
+            error: format!("Error reading build result {}: {}", out_link.display(), e),
+        })
+}
+
+fn create_profile(
+    flake: PathBuf,
+    container_id: &str,
+    request_id: RequestId,
+) -> Option<RequestIdResult> {
+    let container_profile = containerprofile().join(container_id);
+    log::info!("Creating profile {}", container_profile.display());
+
+    if let Err(e) = create_dir_all(&container_profile) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error creating nixos profile {}: {}",
+                container_profile.display(),
+                e
+            ),
+        });
+    }
+
+    let mut cli_command = Command::new(format!("{}nix", nix()));
+    cli_command
+        .env("NIX_REMOTE", "daemon")
+        .env("NIX_BUILD_CORES", buildcores().to_string())
+        .arg("build")
+        .arg("--profile")
+        .arg(container_profile.join("system"))
+        .arg(format!(
+            "{}#nixosConfigurations.container.config.system.build.toplevel",
+            flake.to_string_lossy()
+        ));
+
+    if let Err(e) = execute_command(cli_command, CommandExecutionMode::Stream { request_id }) {
+        return Some(RequestIdResult::Error {
+            error: format!("Error building configuration {}: {}", flake.display(), e),
+        });
+    }
+
+    None
+}
+fn switch_profile(
+    container_id: &str,
+    generation: Option<u64>,
+    request_id: RequestId,
+) -> RequestIdResult {
+    let container_profile = containerprofile().join(container_id).join("system");
+    let previous = current_generation(&container_profile);
+
+    if let Err(e) = profile::switch_generation(&container_profile, generation, request_id) {
+        return RequestIdResult::Error {
+            error: format!(
+                "Error switching nixos profile {}: {}",
+                container_profile.display(),
+                e
+            ),
+        };
+    }
+
+    let mut command = Command::new(format!("{}systemctl", systemd()));
+    command
+        .arg("restart")
+        .arg(format!("container@{}", container_id));
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+        let mut error = format!("Error restarting nixos container {}: {}", container_id, e);
+        // Back to the generation the container was running, as with a failed set
+        if let Some(previous) = previous {
+            match profile::switch_generation(&container_profile, Some(previous), request_id) {
+                Ok(()) => {
+                    let mut command = Command::new(format!("{}systemctl", systemd()));
+                    command
+                        .arg("restart")
+                        .arg(format!("container@{}", container_id));
+                    let _ = execute_command(command, CommandExecutionMode::Stream { request_id });
+                }
+                Err(e) => {
+                    error = format!(
+                        "{}. Switching back to generation {} failed: {}",
+                        error, previous, e
+                    );
+                }
+            }
+        }
+        return RequestIdResult::Error { error };
+    }
+
+    RequestIdResult::Success { body: None }
+}
+
+fn remove_profile(container_id: &str) -> Option<RequestIdResult> {
+    let container_profile = containerprofile().join(container_id);
+    if let Err(e) = remove_dir_all(&container_profile) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error deleting nixos profile {}: {}",
+                container_profile.display(),
+                e
+            ),
+        });
+    }
+
+    None
+}
+
+fn create_state_dir(container_id: &str) -> Option<RequestIdResult> {
+    let state_dir = containerstate().join(container_id);
+    log::info!("Creating state dir {}", state_dir.display());
+
+    if let Err(e) = create_dir_all(&state_dir) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error creating nixos container state directory {}: {}",
+                state_dir.display(),
+                e
+            ),
+        });
+    }
+
+    // Create xnode-config in container state
+    let xnode_config_in_dir = state_dir.join("xnode-config");
+    if let Err(e) = create_dir_all(&xnode_config_in_dir) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error creating container state xnode-config directory {}: {}",
+                xnode_config_in_dir.display(),
+                e
+            ),
+        });
+    }
+
+    // Create xnode-config in container config
+    let xnode_config_out_dir = containersettings().join(container_id).join("xnode-config");
+    if let Err(e) = create_dir_all(&xnode_config_out_dir) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error creating container config xnode-config directory {}: {}",
+                xnode_config_out_dir.display(),
+                e
+            ),
+        });
+    }
+
+    // Set container host platform to same as host
+    match host_platform() {
+        Ok(platform) => {
+            let path = xnode_config_out_dir.join("host-platform");
+            if let Err(e) = write(&path, platform) {
+                return Some(RequestIdResult::Error {
+                    error: format!("Error writing host platform to {}: {}", path.display(), e),
+                });
+            }
+        }
+        Err(error) => {
+            return Some(RequestIdResult::Error { error });
+        }
+    }
+
+    // Set container hostname to it's container id
+    {
+        let path = xnode_config_out_dir.join("hostname");
+        if let Err(e) = write(&path, container_id) {
+            return Some(RequestIdResult::Error {
+                error: format!("Error writing hostname to {}: {}", path.display(), e),
+            });
+        }
     }
 
     // Copy all other files from the xnode-config dir in the container, including state version
     if let Err(e) = copy_dir_all(&xnode_config_in_dir, &xnode_config_out_dir) {
         return Some(RequestIdResult::Error {
             error: format!(
-                "Error copying {} to {}: {}",
-                xnode_config_in_dir.display(),
-                xnode_config_out_dir.display(),
+                "Error copying {} to {}: {}",
+                xnode_config_in_dir.display(),
+                xnode_config_out_dir.display(),
+                e
+            ),
+        });
+    }
+
+    None
+}
+fn host_platform() -> Result<String, String> {
+    let mut command = Command::new(format!("{}uname", coreutils()));
+    command.arg("-m");
+    match execute_command(command, CommandExecutionMode::Simple) {
+        Ok(bytes) => Ok(format!("{}-linux", String::from_utf8_lossy(&bytes).trim())),
+        Err(e) => Err(format!("Error getting host platform: {}", e)),
+    }
+}
+
+fn remove_state_dir(container_id: &str, request_id: RequestId) -> Option<RequestIdResult> {
+    remove_immutable_dir(&containerstate().join(container_id), request_id)
+}
+
+fn remove_immutable_dir(state_dir: &Path, request_id: RequestId) -> Option<RequestIdResult> {
+    // /var/empty is immutable, preventing deletion
+    let mut cli_command = Command::new(format!("{}chattr", e2fsprogs()));
+    cli_command
+        .arg("-i")
+        .arg(state_dir.join("var").join("empty"));
+
+    let _ = execute_command(cli_command, CommandExecutionMode::Stream { request_id });
+
+    if remove_dir_all(state_dir).is_err() {
+        // Ignore first error: Directory not empty (os error 39)
+        if let Err(e) = remove_dir_all(state_dir) {
+            return Some(RequestIdResult::Error {
+                error: format!(
+                    "Error deleting nixos container state directory {}: {}",
+                    state_dir.display(),
+                    e
+                ),
+            });
+        }
+    }
+
+    None
+}
+
+fn create_conf_file(
+    container_id: &str,
+    settings: &ContainerSettings,
+    request_id: RequestId,
+) -> Option<RequestIdResult> {
+    if let Some(limits) = &settings.resource_limits {
+        for (name, weight) in [("CPU", limits.cpu_weight), ("IO", limits.io_weight)] {
+            if let Some(weight) = weight
+                && !(1..=10000).contains(&weight)
+            {
+                return Some(RequestIdResult::Error {
+                    error: format!(
+                        "{} weight {} out of range, should be between 1 and 10000",
+                        name, weight
+                    ),
+                });
+            }
+        }
+    }
+
+    if let Some(ports) = &settings.ports
+        && let Some(e) = validate_ports(container_id, ports, &settings.network)
+    {
+        return Some(e);
+    }
+    if let Some(devices) = &settings.devices
+        && let Some(e) = validate_devices(devices)
+    {
+        return Some(e);
+    }
+    if let Some(mounts) = &settings.mounts {
+        if let Some(e) = validate_mounts(mounts) {
+            return Some(e);
+        }
+
+        for mount in mounts {
+            if let MountSource::Volume(name) = &mount.source {
+                let path = volumedir().join(name);
+                if let Err(e) = create_dir_all(&path) {
+                    return Some(RequestIdResult::Error {
+                        error: format!("Error creating volume {}: {}", path.display(), e),
+                    });
+                }
+            }
+        }
+    }
+
+    let conf_file = conf_file(container_id);
+    log::info!("Creating conf file {}", conf_file.display());
+
+    let nspawn_flags: Vec<String> = []
+        .into_iter()
+        .chain(
+            settings
+                .network
+                .as_ref()
+                .map(|network_zone| vec![format!("--network-zone={}", network_zone)])
+                .unwrap_or_default(),
+        )
+        .chain(
+            settings
+                .nvidia_gpus
+                .as_ref()
+                .map(|gpus| {
+                    gpus.iter()
+                        .map(|gpu_id| gpu_id.to_string())
+                        .chain(
+                            ["ctl", "-modeset", "-uvm", "-uvm-tools"]
+                                .into_iter()
+                                .map(|str| str.to_string()),
+                        )
+                        .map(|postfix| format!("--bind-ro=/dev/nvidia{}", postfix))
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default(),
+        )
+        .chain(
+            settings
+                .devices
+                .as_ref()
+                .map(|devices| {
+                    devices
+                        .iter()
+                        .map(|device| format!("--bind={}", device_path(device)))
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default(),
+        )
+        .chain(
+            settings
+                .ports
+                .as_ref()
+                .map(|ports| {
+                    ports
+                        .iter()
+                        .map(|port| {
+                            format!(
+                                "--port={}:{}:{}",
+                                protocol_name(port.protocol),
+                                port.host_port,
+                                port.container_port
+                            )
+                        })
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default(),
+        )
+        .chain(
+            settings
+                .mounts
+                .as_ref()
+                .map(|mounts| {
+                    mounts
+                        .iter()
+                        .map(|mount| {
+                            format!(
+                                "--{}={}:{}{}",
+                                if mount.read_only { "bind-ro" } else { "bind" },
+                                match &mount.source {
+                                    MountSource::Host(path) => path.to_string(),
+                                    MountSource::Volume(name) =>
+                                        volumedir().join(name).to_string_lossy().to_string(),
+                                },
+                                mount.container_path,
+                                if mount.idmap { ":idmap" } else { "" }
+                            )
+                        })
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default(),
+        )
+        .collect();
+
+    // Only the nspawn flags are managed here, other keys (e.g. written by nixos-container) are kept
+    let mut conf = if conf_file.exists() {
+        match ContainerConf::read(&conf_file) {
+            Ok(conf) => conf,
+            Err(e) => {
+                return Some(RequestIdResult::Error {
+                    error: format!(
+                        "Error reading nixos container configuration file {}: {}",
+                        conf_file.display(),
+                        e
+                    ),
+                });
+            }
+        }
+    } else {
+        ContainerConf::default()
+    };
+    conf.extra_nspawn_flags = nspawn_flags;
+    if let Err(e) = conf.write(&conf_file) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error writing nixos container configuration file {}: {}",
+                conf_file.display(),
+                e
+            ),
+        });
+    }
+
+    let systemd_conf_file = systemd_conf_file(container_id);
+    log::info!("Creating systemd conf file {}", conf_file.display());
+
+    if let Some(dir) = systemd_conf_file.parent()
+        && let Err(e) = create_dir_all(dir)
+    {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error creating nixos container systemd configuration folder {}: {}",
+                dir.display(),
+                e
+            ),
+        });
+    }
+
+    let systemd_config: Vec<String> = ["[Service]"]
+        .into_iter()
+        .map(|str| str.to_string())
+        .chain(
+            settings
+                .nvidia_gpus
+                .as_ref()
+                .map(|gpus| {
+                    gpus.iter()
+                        .map(|gpu_id| gpu_id.to_string())
+                        .chain(
+                            ["ctl", "-caps*", "-modeset", "-uvm", "-uvm-tools"]
+                                .into_iter()
+                                .map(|str| str.to_string()),
+                        )
+                        .map(|postfix| format!("DeviceAllow=/dev/nvidia{} rw", postfix))
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default(),
+        )
+        .chain(
+            settings
+                .devices
+                .as_ref()
+                .map(|devices| {
+                    devices
+                        .iter()
+                        .map(|device| format!("DeviceAllow={} rw", device_path(device)))
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default(),
+        )
+        .chain(
+            settings
+                .resource_limits
+                .as_ref()
+                .map(|limits| {
+                    [
+                        limits.cpu_quota.map(|quota| format!("CPUQuota={}%", quota)),
+                        limits
+                            .cpu_weight
+                            .map(|weight| format!("CPUWeight={}", weight)),
+                        limits
+                            .memory_max
+                            .map(|bytes| format!("MemoryMax={}", bytes)),
+                        limits
+                            .memory_high
+                            .map(|bytes| format!("MemoryHigh={}", bytes)),
+                        limits
+                            .io_weight
+                            .map(|weight| format!("IOWeight={}", weight)),
+                        limits.tasks_max.map(|tasks| format!("TasksMax={}", tasks)),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<String>>()
+                })
+                .unwrap_or_default(),
+        )
+        .collect();
+
+    if let Err(e) = write(&systemd_conf_file, systemd_config.join("\n")) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error writing nixos container systemd configuration file {}: {}",
+                systemd_conf_file.display(),
+                e
+            ),
+        });
+    }
+
+    let mut reload_command = Command::new(format!("{}systemctl", systemd()));
+    reload_command.arg("daemon-reload");
+    if let Err(e) = execute_command(reload_command, CommandExecutionMode::Stream { request_id }) {
+        return Some(RequestIdResult::Error {
+            error: format!("Error reloading systemd daemon: {}", e),
+        });
+    }
+
+    None
+}
+
+fn validate_ports(
+    container_id: &str,
+    ports: &[PortForward],
+    network: &Option<String>,
+) -> Option<RequestIdResult> {
+    // nspawn only forwards ports into a private network
+    if !ports.is_empty() && network.is_none() {
+        return Some(RequestIdResult::Error {
+            error: "Port forwarding requires a network".to_string(),
+        });
+    }
+
+    for (i, port) in ports.iter().enumerate() {
+        if port.host_port == 0 || port.container_port == 0 {
+            return Some(RequestIdResult::Error {
+                error: format!(
+                    "Invalid port forward {} -> {} ({}), ports should be between 1 and 65535",
+                    port.host_port,
+                    port.container_port,
+                    protocol_name(port.protocol)
+                ),
+            });
+        }
+
+        if ports[..i]
+            .iter()
+            .any(|p| p.host_port == port.host_port && p.protocol == port.protocol)
+        {
+            return Some(RequestIdResult::Error {
+                error: format!(
+                    "Host port {} ({}) is forwarded multiple times",
+                    port.host_port,
+                    protocol_name(port.protocol)
+                ),
+            });
+        }
+    }
+
+    let nspawn_flags = match all_nspawn_flags() {
+        Ok(nspawn_flags) => nspawn_flags,
+        Err(error) => return Some(RequestIdResult::Error { error }),
+    };
+    for (other_container_id, nspawn_flags) in nspawn_flags {
+        if other_container_id == container_id {
+            continue;
+        }
+
+        if let Some(port) = nspawn_flags
+            .iter()
+            .filter_map(|flag| parse_port(flag))
+            .find(|other| {
+                ports
+                    .iter()
+                    .any(|p| p.host_port == other.host_port && p.protocol == other.protocol)
+            })
+        {
+            return Some(RequestIdResult::Error {
+                error: format!(
+                    "Host port {} ({}) is already forwarded to container {}",
+                    port.host_port,
+                    protocol_name(port.protocol),
+                    other_container_id
+                ),
+            });
+        }
+    }
+
+    None
+}
+
+fn validate_mounts(mounts: &[Mount]) -> Option<RequestIdResult> {
+    for mount in mounts {
+        let valid_source = match &mount.source {
+            MountSource::Host(path) => valid_mount_path(path),
+            MountSource::Volume(name) => valid_name(name),
+        };
+        if !valid_source {
+            return Some(RequestIdResult::Error {
+                error: format!("Invalid mount source {:?}", mount.source),
+            });
+        }
+        if !valid_mount_path(&mount.container_path) {
+            return Some(RequestIdResult::Error {
+                error: format!("Invalid mount container path {}", mount.container_path),
+            });
+        }
+        // Binds into /dev are reserved for device passthrough
+        if mount.container_path.starts_with("/dev/") {
+            return Some(RequestIdResult::Error {
+                error: format!(
+                    "Mount container path {} is inside /dev, use devices instead",
+                    mount.container_path
+                ),
+            });
+        }
+    }
+
+    None
+}
+
+// Absolute, and representable in a --bind flag (which is split on whitespace and ':')
+fn valid_mount_path(path: &str) -> bool {
+    path.len() > 1
+        && path.starts_with("/")
+        && !path.contains(":")
+        && !path.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn validate_devices(devices: &[Device]) -> Option<RequestIdResult> {
+    for device in devices {
+        let path = device_path(device);
+        if !path.starts_with("/dev/")
+            || path.contains(":")
+            || path.split("/").any(|part| part == "..")
+        {
+            return Some(RequestIdResult::Error {
+                error: format!("Invalid device path {}", path),
+            });
+        }
+        match metadata(&path) {
+            Ok(metadata) => {
+                if !metadata.file_type().is_char_device() {
+                    return Some(RequestIdResult::Error {
+                        error: format!("Device {} is not a character device", path),
+                    });
+                }
+            }
+            Err(e) => {
+                return Some(RequestIdResult::Error {
+                    error: format!("Device {} not available on host: {}", path, e),
+                });
+            }
+        }
+    }
+
+    None
+}
+
+pub fn device_path(device: &Device) -> String {
+    match device {
+        Device::RenderNode(id) => format!("/dev/dri/renderD{}", id),
+        Device::Kfd => "/dev/kfd".to_string(),
+        Device::Char(path) => path.to_string(),
+    }
+}
+
+fn parse_device(path: &str) -> Device {
+    if path == "/dev/kfd" {
+        return Device::Kfd;
+    }
+    if let Some(id) = path.strip_prefix("/dev/dri/renderD")
+        && let Ok(id) = id.parse::<u32>()
+    {
+        return Device::RenderNode(id);
+    }
+
+    Device::Char(path.to_string())
+}
+
+// Parses nspawn --bind=SRC[:DST[:OPTIONS]] and --bind-ro=SRC[:DST[:OPTIONS]] flags
+fn parse_mount(flag: &str) -> Option<Mount> {
+    let (bind, read_only) = match flag.strip_prefix("--bind-ro=") {
+        Some(bind) => (bind, true),
+        None => (flag.strip_prefix("--bind=")?, false),
+    };
+    let mut parts = bind.split(":");
+    let source = parts.next()?;
+    let container_path = parts.next().unwrap_or(source);
+    let idmap = parts
+        .next()
+        .is_some_and(|options| options.split(",").any(|option| option == "idmap"));
+
+    // Only a direct child of the volume dir (compared per path component) is a named volume
+    let volume = Path::new(source)
+        .strip_prefix(volumedir())
+        .ok()
+        .and_then(|volume| match volume.components().collect::<Vec<_>>()[..] {
+            [Component::Normal(name)] => name.to_str(),
+            _ => None,
+        })
+        .filter(|name| valid_name(name));
+    Some(Mount {
+        source: match volume {
+            Some(volume) => MountSource::Volume(volume.to_string()),
+            None => MountSource::Host(source.to_string()),
+        },
+        container_path: container_path.to_string(),
+        read_only,
+        idmap,
+    })
+}
+
+fn volume_users(name: &str, nspawn_flags: &[(String, Vec<String>)]) -> Vec<String> {
+    nspawn_flags
+        .iter()
+        .filter(|(_, flags)| {
+            flags
+                .iter()
+                .filter_map(|flag| parse_mount(flag))
+                .any(|mount| matches!(mount.source, MountSource::Volume(volume) if volume == name))
+        })
+        .map(|(container_id, _)| container_id.to_string())
+        .collect()
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(".")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ['-', '_', '.'].contains(&c))
+}
+
+fn container_exists(container_id: &str) -> bool {
+    containersettings().join(container_id).exists() || conf_file(container_id).exists()
+}
+
+// Sets up a new container, removing what was already created if that fails
+fn create_container(
+    container_id: &str,
+    change: &ContainerChange,
+    request_id: RequestId,
+) -> RequestIdResult {
+    let result = set_container(container_id, change, request_id);
+    if let RequestIdResult::Error { error } = &result
+        && let Some(RequestIdResult::Error { error: e }) =
+            remove_container_dirs(container_id, request_id)
+    {
+        return RequestIdResult::Error {
+            error: format!(
+                "{}. Cleaning up container {} failed: {}",
+                error, container_id, e
+            ),
+        };
+    }
+
+    result
+}
+
+fn remove_container_dirs(container_id: &str, request_id: RequestId) -> Option<RequestIdResult> {
+    if containerstate().join(container_id).exists()
+        && let Some(e) = remove_state_dir(container_id, request_id)
+    {
+        return Some(e);
+    }
+
+    let path = containersettings().join(container_id);
+    if path.exists()
+        && let Err(e) = remove_dir_all(&path)
+    {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error deleting container folder config {}: {}",
+                path.display(),
+                e
+            ),
+        });
+    }
+
+    None
+}
+
+fn clone_state_dir(
+    container_id: &str,
+    target: &str,
+    request_id: RequestId,
+) -> Option<RequestIdResult> {
+    let source_dir = containerstate().join(container_id);
+    let target_dir = containerstate().join(target);
+    if !source_dir.exists() {
+        return None;
+    }
+
+    // Stop the container while copying its state, otherwise files could change mid-copy
+    let running = is_container_running(container_id);
+    if running {
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg("stop")
+            .arg(format!("container@{}", container_id));
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return Some(RequestIdResult::Error {
+                error: format!("Error stopping nixos container {}: {}", container_id, e),
+            });
+        }
+    }
+
+    // Archive mode keeps ownership, permissions and ACLs of the container files
+    let mut command = Command::new(format!("{}cp", coreutils()));
+    command
+        .arg("--archive")
+        .arg("--reflink=auto")
+        .arg(&source_dir)
+        .arg(&target_dir);
+    let result = execute_command(command, CommandExecutionMode::Stream { request_id });
+
+    if running {
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg("start")
+            .arg(format!("container@{}", container_id));
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            log::warn!(
+                "Could not start nixos container {} after clone: {}",
+                container_id,
+                e
+            );
+        }
+    }
+
+    if let Err(e) = result {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error copying container state {} to {}: {}",
+                source_dir.display(),
+                target_dir.display(),
+                e
+            ),
+        });
+    }
+
+    // A new machine id is generated on first boot
+    let machine_id = target_dir.join("etc").join("machine-id");
+    if machine_id.exists()
+        && let Err(e) = remove_file(&machine_id)
+    {
+        return Some(RequestIdResult::Error {
+            error: format!("Error removing machine id {}: {}", machine_id.display(), e),
+        });
+    }
+
+    // Would otherwise be copied over the hostname written by create_state_dir
+    let hostname = target_dir.join("xnode-config").join("hostname");
+    if hostname.exists()
+        && let Err(e) = write(&hostname, target)
+    {
+        return Some(RequestIdResult::Error {
+            error: format!("Error writing hostname to {}: {}", hostname.display(), e),
+        });
+    }
+
+    None
+}
+
+// Moves everything named after the container id, moving it all back if any step fails
+fn move_container(container_id: &str, target: &str, request_id: RequestId) -> Result<(), String> {
+    let paths = [
+        (containersettings(), containersettings()),
+        (containerstate(), containerstate()),
+        (containerprofile(), containerprofile()),
+        (backupdir(), backupdir()),
+    ]
+    .map(|(from, to)| (from.join(container_id), to.join(target)))
+    .into_iter()
+    .chain([
+        (conf_file(container_id), conf_file(target)),
+        (systemd_conf_dir(container_id), systemd_conf_dir(target)),
+    ]);
+
+    let mut moved: Vec<(PathBuf, PathBuf)> = vec![];
+    let result = paths
+        .filter(|(from, _)| from.exists())
+        .try_for_each(|(from, to)| {
+            log::info!("Moving {} to {}", from.display(), to.display());
+            std::fs::rename(&from, &to).map_err(|e| {
+                format!("Error moving {} to {}: {}", from.display(), to.display(), e)
+            })?;
+            moved.push((from, to));
+            Ok(())
+        })
+        .and_then(|()| write_hostname(target))
+        .and_then(|()| reload_systemd(request_id))
+        .and_then(|()| {
+            // Hostname is part of the system configuration
+            match create_profile(containersettings().join(target), target, request_id) {
+                Some(RequestIdResult::Error { error }) => Err(error),
+                _ => Ok(()),
+            }
+        });
+
+    if let Err(error) = result {
+        let mut errors: Vec<String> = moved
+            .iter()
+            .rev()
+            .filter_map(|(from, to)| {
+                std::fs::rename(to, from)
+                    .map_err(|e| {
+                        format!("Error moving {} to {}: {}", to.display(), from.display(), e)
+                    })
+                    .err()
+            })
+            .collect();
+        if !moved.is_empty() {
+            errors.extend(write_hostname(container_id).err());
+            errors.extend(reload_systemd(request_id).err());
+        }
+
+        return Err(if errors.is_empty() {
+            format!("{}. Reverted rename of container {}", error, container_id)
+        } else {
+            format!(
+                "{}. Reverting rename of container {} failed: {}",
+                error,
+                container_id,
+                errors.join(", ")
+            )
+        });
+    }
+
+    Ok(())
+}
+
+fn write_hostname(container_id: &str) -> Result<(), String> {
+    [
+        containersettings().join(container_id),
+        containerstate().join(container_id),
+    ]
+    .map(|dir| dir.join("xnode-config").join("hostname"))
+    .into_iter()
+    .filter(|path| path.exists())
+    .try_for_each(|path| {
+        write(&path, container_id)
+            .map_err(|e| format!("Error writing hostname to {}: {}", path.display(), e))
+    })
+}
+
+fn reload_systemd(request_id: RequestId) -> Result<(), String> {
+    let mut command = Command::new(format!("{}systemctl", systemd()));
+    command.arg("daemon-reload");
+    execute_command(command, CommandExecutionMode::Stream { request_id })
+        .map(|_| ())
+        .map_err(|e| format!("Error reloading systemd daemon: {}", e))
+}
+
+fn import_container(
+    archive: &Path,
+    container: Option<ContainerId>,
+    request_id: RequestId,
+) -> RequestIdResult {
+    // zstd frame magic number, otherwise a plain tar archive
+    let compressed = File::open(archive)
+        .and_then(|mut file| {
+            let mut magic = [0; 4];
+            file.read_exact(&mut magic).map(|()| magic)
+        })
+        .is_ok_and(|magic| magic == [0x28, 0xB5, 0x2F, 0xFD]);
+
+    let manifest_dir = match private_temp_dir("import-manifest") {
+        Ok(manifest_dir) => manifest_dir,
+        Err(e) => {
+            return RequestIdResult::Error {
+                error: format!("Error creating import manifest dir: {}", e),
+            };
+        }
+    };
+    let manifest = match extract_export(
+        archive,
+        compressed,
+        "manifest.json",
+        &manifest_dir,
+        request_id,
+    ) {
+        Some(e) => Err(e),
+        None => {
+            let path = manifest_dir.join("manifest.json");
+            read_to_string(&path)
+                .map_err(|e| format!("Error reading manifest {}: {}", path.display(), e))
+                .and_then(|file| {
+                    serde_json::from_str::<ContainerManifest>(&file)
+                        .map_err(|e| format!("Error parsing manifest {}: {}", path.display(), e))
+                })
+                .map_err(|error| RequestIdResult::Error { error })
+        }
+    };
+    if let Err(e) = remove_dir_all(&manifest_dir) {
+        log::warn!(
+            "Could not remove import manifest dir {}: {}",
+            manifest_dir.display(),
+            e
+        );
+    }
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => return e,
+    };
+
+    let Some(platform) = &manifest.host_platform else {
+        return RequestIdResult::Error {
+            error: format!(
+                "Export of container {} does not specify its host platform",
+                manifest.container
+            ),
+        };
+    };
+    match host_platform() {
+        Ok(host) => {
+            if &host != platform {
+                return RequestIdResult::Error {
+                    error: format!(
+                        "Container {} was exported from a {} host, this host is {}",
+                        manifest.container, platform, host
+                    ),
+                };
+            }
+        }
+        Err(error) => return RequestIdResult::Error { error },
+    }
+
+    let container_id = container.unwrap_or(manifest.container);
+    if container_exists(&container_id) {
+        return RequestIdResult::Error {
+            error: format!("Container {} already exists", container_id),
+        };
+    }
+
+    let mut members = vec![("settings", containersettings())];
+    if manifest.state {
+        members.push(("state", containerstate()));
+    }
+    let extracted = match members.into_iter().find_map(|(member, dir)| {
+        extract_export(
+            archive,
+            compressed,
+            member,
+            &dir.join(&container_id),
+            request_id,
+        )
+    }) {
+        Some(RequestIdResult::Error { error }) => Err(error),
+        _ => write_hostname(&container_id),
+    };
+    if let Err(error) = extracted {
+        let _ = remove_container_dirs(&container_id, request_id);
+        return RequestIdResult::Error { error };
+    }
+
+    let change = ContainerChange {
+        settings: manifest.settings,
+        update_inputs: None,
+        mode: None,
+    };
+    create_container(&container_id, &change, request_id)
+}
+
+// Extracts a file or directory (without the directory itself) from an exported container archive
+fn extract_export(
+    archive: &Path,
+    compressed: bool,
+    member: &str,
+    destination: &Path,
+    request_id: RequestId,
+) -> Option<RequestIdResult> {
+    if let Err(e) = create_dir_all(destination) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error creating extraction folder {}: {}",
+                destination.display(),
+                e
+            ),
+        });
+    }
+
+    let mut command = Command::new(format!("{}tar", gnutar()));
+    command.arg("--extract");
+    if compressed {
+        command.arg(format!("--use-compress-program={}zstd", zstd()));
+    }
+    command
+        .arg("--file")
+        .arg(archive)
+        .arg("--acls")
+        .arg("--xattrs")
+        .arg("--numeric-owner")
+        .arg("--same-owner")
+        .arg("--same-permissions")
+        .arg("--directory")
+        .arg(destination);
+    if member != "manifest.json" {
+        command.arg("--strip-components=1");
+    }
+    command.arg(member);
+
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+        return Some(RequestIdResult::Error {
+            error: format!(
+                "Error extracting {} from {}: {}",
+                member,
+                archive.display(),
                 e
             ),
         });
@@ -401,123 +2549,199 @@ fn create_state_dir(container_id: &str) -> Option<RequestIdResult> {
 
     None
 }
-fn remove_state_dir(container_id: &str, request_id: RequestId) -> Option<RequestIdResult> {
-    let state_dir = containerstate().join(container_id);
 
-    // /var/empty is immutable, preventing deletion
-    let mut cli_command = Command::new(format!("{}chattr", e2fsprogs()));
-    cli_command
-        .arg("-i")
-        .arg(state_dir.join("var").join("empty"));
+// Renames a directory inside a tar archive, for directories archived relative to /
+fn tar_transform(dir: &Path, name: &str) -> String {
+    let dir = dir.to_string_lossy();
+    let escaped: String = dir
+        .trim_start_matches("/")
+        .chars()
+        .flat_map(|c| {
+            if ".[]*^$\\,".contains(c) {
+                vec!['\\', c]
+            } else {
+                vec![c]
+            }
+        })
+        .collect();
 
-    let _ = execute_command(cli_command, CommandExecutionMode::Stream { request_id });
+    // S: leave symlink targets untouched
+    format!("--transform=s,^{},{},S", escaped, name)
+}
 
-    if remove_dir_all(&state_dir).is_err() {
-        // Ignore first error: Directory not empty (os error 39)
-        if let Err(e) = remove_dir_all(&state_dir) {
-            return Some(RequestIdResult::Error {
-                error: format!(
-                    "Error deleting nixos container state directory {}: {}",
-                    state_dir.display(),
-                    e
-                ),
-            });
+fn template_file(name: &str) -> PathBuf {
+    templatedir().join(format!("{}.json", name))
+}
+
+fn read_template(name: &str) -> Result<ContainerTemplate, String> {
+    let path = template_file(name);
+    let settings = read_to_string(&path)
+        .map_err(|e| format!("Error reading template {}: {}", path.display(), e))
+        .and_then(|file| {
+            serde_json::from_str::<ContainerSettings>(&file)
+                .map_err(|e| format!("Error parsing template {}: {}", path.display(), e))
+        })?;
+
+    let mut parameters = vec![];
+    map_strings(json!(settings), &mut |text| {
+        replace_parameters(&text, &HashMap::new(), &mut parameters)
+    });
+    parameters.retain(|parameter| parameter != "container");
+    parameters.sort();
+
+    Ok(ContainerTemplate {
+        name: name.to_string(),
+        parameters,
+        settings,
+    })
+}
+
+fn map_strings(value: Value, f: &mut impl FnMut(String) -> String) -> Value {
+    match value {
+        Value::String(text) => Value::String(f(text)),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| map_strings(value, f))
+                .collect(),
+        ),
+        Value::Object(values) => Value::Object(
+            values
+                .into_iter()
+                .map(|(key, value)| (key, map_strings(value, f)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+// Replaces {{parameter}} placeholders, collecting the parameters without a value in missing
+fn replace_parameters(
+    text: &str,
+    parameters: &HashMap<String, String>,
+    missing: &mut Vec<String>,
+) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(index) = rest.find("{{") {
+        result.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        let placeholder = rest[2..]
+            .find("}}")
+            .map(|end| &rest[2..end + 2])
+            .filter(|name| valid_name(name));
+        match placeholder {
+            Some(name) => {
+                match parameters.get(name) {
+                    Some(value) => result.push_str(value),
+                    None => {
+                        if !missing.iter().any(|parameter| parameter == name) {
+                            missing.push(name.to_string());
+                        }
+                        result.push_str(&rest[..name.len() + 4]);
+                    }
+                }
+                rest = &rest[name.len() + 4..];
+            }
+            None => {
+                // Not a placeholder (e.g. nested nix attribute sets)
+                result.push_str("{{");
+                rest = &rest[2..];
+            }
         }
     }
+    result.push_str(rest);
 
-    None
+    result
 }
 
-fn create_conf_file(
-    container_id: &str,
-    network: &Option<String>,
-    nvidia_gpus: &Option<Vec<u64>>,
-    request_id: RequestId,
-) -> Option<RequestIdResult> {
-    let conf_file = containerconfig().join(format!("{}.conf", container_id));
-    log::info!("Creating conf file {}", conf_file.display());
-
-    let nspawn_flags: Vec<String> = []
-        .into_iter()
-        .chain(
-            network
-                .as_ref()
-                .map(|network_zone| vec![format!("--network-zone={}", network_zone)])
-                .unwrap_or_default(),
-        )
-        .chain(
-            nvidia_gpus
-                .as_ref()
-                .map(|gpus| {
-                    gpus.iter()
-                        .map(|gpu_id| gpu_id.to_string())
-                        .chain(
-                            ["ctl", "-modeset", "-uvm", "-uvm-tools"]
-                                .into_iter()
-                                .map(|str| str.to_string()),
-                        )
-                        .map(|postfix| format!("--bind-ro=/dev/nvidia{}", postfix))
-                        .collect::<Vec<String>>()
-                })
-                .unwrap_or_default(),
+// Nspawn flags of all containers, by container id
+fn all_nspawn_flags() -> Result<Vec<(String, Vec<String>)>, String> {
+    let path = containerconfig();
+    let dir = read_dir(&path).map_err(|e| {
+        format!(
+            "Error reading container config dir {}: {}",
+            path.display(),
+            e
         )
-        .collect();
+    })?;
 
-    if let Err(e) = write(
-        &conf_file,
-        format!("EXTRA_NSPAWN_FLAGS=\"{}\"", nspawn_flags.join(" ")),
-    ) {
+    Ok(dir
+        .filter_map(|f| f.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let container_id = name.strip_suffix(".conf")?.to_string();
+            let conf = ContainerConf::read(&entry.path()).ok()?;
+            Some((container_id, conf.extra_nspawn_flags))
+        })
+        .collect())
+}
+
+// Parses nspawn --port=[PROTOCOL:]HOSTPORT[:CONTAINERPORT] flags
+fn parse_port(flag: &str) -> Option<PortForward> {
+    let port = flag.strip_prefix("--port=")?;
+    let mut parts: Vec<&str> = port.split(":").collect();
+    let protocol = match parts.first() {
+        Some(&"tcp") => {
+            parts.remove(0);
+            Protocol::Tcp
+        }
+        Some(&"udp") => {
+            parts.remove(0);
+            Protocol::Udp
+        }
+        _ => Protocol::Tcp,
+    };
+    let host_port = parts.first()?.parse::<u16>().ok()?;
+    let container_port = match parts.get(1) {
+        Some(container_port) => container_port.parse::<u16>().ok()?,
+        None => host_port,
+    };
+
+    Some(PortForward {
+        host_port,
+        container_port,
+        protocol,
+    })
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    }
+}
+
+fn conf_file(container_id: &str) -> PathBuf {
+    containerconfig().join(format!("{}.conf", container_id))
+}
+
+fn systemd_conf_dir(container_id: &str) -> PathBuf {
+    systemdconfig().join(format!("container@{}.service.d", container_id))
+}
+
+fn systemd_conf_file(container_id: &str) -> PathBuf {
+    systemd_conf_dir(container_id).join("99-XnodeManager.conf")
+}
+
+fn remove_conf_file(container_id: &str, request_id: RequestId) -> Option<RequestIdResult> {
+    let conf_file = conf_file(container_id);
+    if let Err(e) = remove_file(&conf_file) {
         return Some(RequestIdResult::Error {
             error: format!(
-                "Error writing nixos container configuration file {}: {}",
+                "Error deleting nixos container configuration file {}: {}",
                 conf_file.display(),
                 e
             ),
         });
     }
 
-    let systemd_conf_file = systemdconfig()
-        .join(format!("container@{}.service.d", container_id))
-        .join("99-XnodeManager.conf");
-    log::info!("Creating systemd conf file {}", conf_file.display());
-
-    if let Some(dir) = systemd_conf_file.parent() {
-        if let Err(e) = create_dir_all(dir) {
-            return Some(RequestIdResult::Error {
-                error: format!(
-                    "Error creating nixos container systemd configuration folder {}: {}",
-                    dir.display(),
-                    e
-                ),
-            });
-        }
-    }
-
-    let systemd_config: Vec<String> = ["[Service]"]
-        .into_iter()
-        .map(|str| str.to_string())
-        .chain(
-            nvidia_gpus
-                .as_ref()
-                .map(|gpus| {
-                    gpus.iter()
-                        .map(|gpu_id| gpu_id.to_string())
-                        .chain(
-                            ["ctl", "-caps*", "-modeset", "-uvm", "-uvm-tools"]
-                                .into_iter()
-                                .map(|str| str.to_string()),
-                        )
-                        .map(|postfix| format!("DeviceAllow=/dev/nvidia{} rw", postfix))
-                        .collect::<Vec<String>>()
-                })
-                .unwrap_or_default(),
-        )
-        .collect();
-
-    if let Err(e) = write(&systemd_conf_file, systemd_config.join("\n")) {
+    let systemd_conf_file = systemd_conf_file(container_id);
+    if let Err(e) = remove_file(&systemd_conf_file) {
         return Some(RequestIdResult::Error {
             error: format!(
-                "Error writing nixos container systemd configuration file {}: {}",
+                "Error deleting nixos container systemd configuration file {}: {}",
                 systemd_conf_file.display(),
                 e
             ),
@@ -535,38 +2759,365 @@ fn create_conf_file(
     None
 }
 
-fn remove_conf_file(container_id: &str, request_id: RequestId) -> Option<RequestIdResult> {
-    let conf_file = containerconfig().join(format!("{}.conf", container_id));
-    if let Err(e) = remove_file(&conf_file) {
+pub fn create_backup(container_id: &str, request_id: RequestId) -> Result<String, RequestIdResult> {
+    let backup_id = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+        .to_string();
+    let backup_dir = backupdir().join(container_id).join(&backup_id);
+    log::info!("Creating backup {}", backup_dir.display());
+
+    if let Err(e) = create_dir_all(&backup_dir) {
+        return Err(RequestIdResult::Error {
+            error: format!(
+                "Error creating container backup folder {}: {}",
+                backup_dir.display(),
+                e
+            ),
+        });
+    }
+
+    // Stop the container while archiving its state, otherwise files could change mid-backup
+    let running = is_container_running(container_id);
+    if running {
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg("stop")
+            .arg(format!("container@{}", container_id));
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            let _ = remove_dir_all(&backup_dir);
+            return Err(RequestIdResult::Error {
+                error: format!("Error stopping nixos container {}: {}", container_id, e),
+            });
+        }
+    }
+
+    let error = [
+        ("settings.tar.zst", containersettings().join(container_id)),
+        ("state.tar.zst", containerstate().join(container_id)),
+    ]
+    .into_iter()
+    .find_map(|(archive, source)| create_archive(&source, &backup_dir.join(archive), request_id));
+
+    if running {
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg("start")
+            .arg(format!("container@{}", container_id));
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            log::warn!(
+                "Could not start nixos container {} after backup: {}",
+                container_id,
+                e
+            );
+        }
+    }
+
+    if let Some(e) = error {
+        if let Err(e) = remove_dir_all(&backup_dir) {
+            log::warn!(
+                "Could not remove failed backup {}: {}",
+                backup_dir.display(),
+                e
+            );
+        }
+        return Err(e);
+    }
+
+    Ok(backup_id)
+}
+
+pub fn get_backup_schedule(container_id: &str) -> Result<Option<BackupSchedule>, String> {
+    let path = containersettings()
+        .join(container_id)
+        .join("backup-schedule.json");
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| serde_json::from_str(&file).map_err(|e| e.to_string()))
+        .map(Some)
+        .map_err(|e| {
+            format!(
+                "Could not read container backup schedule {}: {}",
+                path.display(),
+                e
+            )
+        })
+}
+
+pub fn get_backups(container_id: &str) -> Result<Vec<ContainerBackup>, String> {
+    let path = backupdir().join(container_id);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    match read_dir(&path) {
+        Ok(dir) => {
+            let mut container_backups: Vec<ContainerBackup> = dir
+                .filter_map(|f| f.ok())
+                .filter_map(|f| {
+                    let id = f.file_name().into_string().ok()?;
+                    let created = id.parse::<u64>().ok()?;
+                    let size = read_dir(f.path())
+                        .map(|files| {
+                            files
+                                .filter_map(|file| file.ok().and_then(|file| file.metadata().ok()))
+                                .map(|metadata| metadata.len())
+                                .sum()
+                        })
+                        .unwrap_or(0);
+                    Some(ContainerBackup { id, created, size })
+                })
+                .collect();
+            container_backups.sort_by_key(|b| b.created);
+            Ok(container_backups)
+        }
+        Err(e) => Err(format!(
+            "Could not read container backup dir {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+pub fn get_backup_dir(container_id: &str, backup_id: &str) -> Result<PathBuf, String> {
+    // Backup ids are creation timestamps, rejecting anything else prevents escaping the backup dir
+    if backup_id.parse::<u64>().is_err() {
+        return Err(format!("Invalid backup id {}", backup_id));
+    }
+
+    let backup_dir = backupdir().join(container_id).join(backup_id);
+    if !backup_dir.is_dir() {
+        return Err(format!(
+            "Backup {} of container {} does not exist",
+            backup_id, container_id
+        ));
+    }
+
+    Ok(backup_dir)
+}
+
+fn create_archive(source: &Path, archive: &Path, request_id: RequestId) -> Option<RequestIdResult> {
+    let mut command = Command::new(format!("{}tar", gnutar()));
+    command
+        .arg("--create")
+        .arg(format!("--use-compress-program={}zstd", zstd()))
+        .arg("--file")
+        .arg(archive)
+        .arg("--acls")
+        .arg("--xattrs")
+        .arg("--numeric-owner")
+        .arg("--directory")
+        .arg(source)
+        .arg(".");
+
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
         return Some(RequestIdResult::Error {
             error: format!(
-                "Error deleting nixos container configuration file {}: {}",
-                conf_file.display(),
+                "Error archiving {} to {}: {}",
+                source.display(),
+                archive.display(),
                 e
             ),
         });
     }
 
-    let systemd_conf_file = systemdconfig()
-        .join(format!("container@{}.service.d", container_id))
-        .join("99-XnodeManager.conf");
-    if let Err(e) = remove_file(&systemd_conf_file) {
+    None
+}
+
+fn extract_archive(
+    archive: &Path,
+    destination: &Path,
+    request_id: RequestId,
+) -> Option<RequestIdResult> {
+    if let Err(e) = create_dir_all(destination) {
         return Some(RequestIdResult::Error {
             error: format!(
-                "Error deleting nixos container systemd configuration file {}: {}",
-                systemd_conf_file.display(),
+                "Error creating extraction folder {}: {}",
+                destination.display(),
                 e
             ),
         });
     }
 
-    let mut reload_command = Command::new(format!("{}systemctl", systemd()));
-    reload_command.arg("daemon-reload");
-    if let Err(e) = execute_command(reload_command, CommandExecutionMode::Stream { request_id }) {
+    let mut command = Command::new(format!("{}tar", gnutar()));
+    command
+        .arg("--extract")
+        .arg(format!("--use-compress-program={}zstd", zstd()))
+        .arg("--file")
+        .arg(archive)
+        .arg("--acls")
+        .arg("--xattrs")
+        .arg("--numeric-owner")
+        .arg("--same-owner")
+        .arg("--same-permissions")
+        .arg("--directory")
+        .arg(destination);
+
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
         return Some(RequestIdResult::Error {
-            error: format!("Error reloading systemd daemon: {}", e),
+            error: format!(
+                "Error extracting {} to {}: {}",
+                archive.display(),
+                destination.display(),
+                e
+            ),
         });
     }
 
     None
 }
+
+struct RestoreDirs {
+    archive: PathBuf,
+    live: PathBuf,
+    new: PathBuf,
+    old: PathBuf,
+}
+
+// Move the live dir aside and the extracted dir in its place
+fn swap_in_restore(dir: &RestoreDirs) -> Result<(), String> {
+    let had_live = dir.live.exists();
+    if had_live && let Err(e) = std::fs::rename(&dir.live, &dir.old) {
+        return Err(format!(
+            "Error moving {} to {}: {}",
+            dir.live.display(),
+            dir.old.display(),
+            e
+        ));
+    }
+    if let Err(e) = std::fs::rename(&dir.new, &dir.live) {
+        if had_live {
+            let _ = std::fs::rename(&dir.old, &dir.live);
+        }
+        return Err(format!(
+            "Error moving {} to {}: {}",
+            dir.new.display(),
+            dir.live.display(),
+            e
+        ));
+    }
+
+    Ok(())
+}
+
+fn swap_out_restore(dir: &RestoreDirs) -> Result<(), String> {
+    std::fs::rename(&dir.live, &dir.new).map_err(|e| {
+        format!(
+            "Error moving {} back to {}: {}",
+            dir.live.display(),
+            dir.new.display(),
+            e
+        )
+    })?;
+    if dir.old.exists() {
+        std::fs::rename(&dir.old, &dir.live).map_err(|e| {
+            format!(
+                "Error moving {} back to {}: {}",
+                dir.old.display(),
+                dir.live.display(),
+                e
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+fn is_container_running(container_id: &str) -> bool {
+    let mut command = Command::new(format!("{}systemctl", systemd()));
+    command
+        .arg("is-active")
+        .arg("--quiet")
+        .arg(format!("container@{}", container_id));
+
+    execute_command(command, CommandExecutionMode::Simple).is_ok()
+}
+
+fn container_command(container_id: ContainerId, systemd_command: &'static str) -> HttpResponse {
+    return_request_id(Box::new(move |request_id| {
+        let mut command = Command::new(format!("{}systemctl", systemd()));
+        command
+            .arg(systemd_command)
+            .arg(format!("container@{}", container_id));
+
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!(
+                    "Error executing {} on nixos container {}: {}",
+                    systemd_command, container_id, e
+                ),
+            };
+        }
+
+        RequestIdResult::Success { body: None }
+    }))
+}
+
+fn parse_properties(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once("="))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn get_ip_addresses(container_id: &str) -> Vec<String> {
+    // The machine leader runs inside the container network namespace, unlike the service main pid (systemd-nspawn)
+    let mut command = Command::new(format!("{}machinectl", systemd()));
+    command
+        .arg("show")
+        .arg(container_id)
+        .arg("--property=Leader");
+    let leader = match execute_command(command, CommandExecutionMode::Simple) {
+        Ok(output) => match String::from_utf8(output)
+            .ok()
+            .and_then(|output| parse_properties(&output).remove("Leader"))
+        {
+            Some(leader) => leader,
+            None => return vec![],
+        },
+        Err(e) => {
+            log::warn!("Could not get leader of container {}: {}", container_id, e);
+            return vec![];
+        }
+    };
+    let net = Path::new("/proc").join(leader).join("net");
+
+    // IPv4: local host routes in the fib trie, e.g. "|-- 10.0.0.2" followed by "/32 host LOCAL"
+    let mut ip_addresses: Vec<String> = vec![];
+    if let Ok(fib_trie) = read_to_string(net.join("fib_trie")) {
+        let mut last_address: Option<&str> = None;
+        for line in fib_trie.lines() {
+            let line = line.trim();
+            if let Some(address) = line.strip_prefix("|-- ") {
+                last_address = Some(address);
+            } else if line.contains("host LOCAL")
+                && let Some(address) = last_address
+                && !address.starts_with("127.")
+                && !ip_addresses.iter().any(|a| a == address)
+            {
+                ip_addresses.push(address.to_string());
+            }
+        }
+    }
+
+    // IPv6: "<address hex> <index> <prefix length> <scope> <flags> <interface>"
+    if let Ok(if_inet6) = read_to_string(net.join("if_inet6")) {
+        for line in if_inet6.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let (Some(address), Some(interface)) = (fields.first(), fields.last())
+                && *interface != "lo"
+                && let Ok(address) = u128::from_str_radix(address, 16)
+            {
+                ip_addresses.push(Ipv6Addr::from(address).to_string());
+            }
+        }
+    }
+
+    ip_addresses
+}
@@ -1,7 +1,9 @@
 use actix_web::web::ServiceConfig;
 
+pub mod conf;
 pub mod handlers;
 pub mod models;
+pub mod scheduler;
 
 pub fn scope() -> String {
     "/config".to_string()
@@ -11,5 +13,29 @@ pub fn configure(cfg: &mut ServiceConfig) {
     cfg.service(handlers::containers);
     cfg.service(handlers::get);
     cfg.service(handlers::set);
+    cfg.service(handlers::diff);
     cfg.service(handlers::remove);
+    cfg.service(handlers::clone);
+    cfg.service(handlers::rename);
+    cfg.service(handlers::export);
+    cfg.service(handlers::import);
+    cfg.service(handlers::start);
+    cfg.service(handlers::stop);
+    cfg.service(handlers::restart);
+    cfg.service(handlers::status);
+    cfg.service(handlers::generations);
+    cfg.service(handlers::rollback);
+    cfg.service(handlers::switch_generation);
+    cfg.service(handlers::volumes);
+    cfg.service(handlers::create_volume);
+    cfg.service(handlers::remove_volume);
+    cfg.service(handlers::templates);
+    cfg.service(handlers::get_template);
+    cfg.service(handlers::set_template);
+    cfg.service(handlers::remove_template);
+    cfg.service(handlers::instantiate_template);
+    cfg.service(handlers::backups);
+    cfg.service(handlers::backup);
+    cfg.service(handlers::restore);
+    cfg.service(handlers::remove_backup);
 }
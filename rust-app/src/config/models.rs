@@ -1,11 +1,61 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::utils::{mode::ChangeMode, scope::ContainerId};
+
 #[derive(Serialize, Deserialize)]
 pub struct ContainerConfiguration {
     pub flake: String,
     pub flake_lock: Option<String>,
     pub network: Option<String>,
     pub nvidia_gpus: Option<Vec<u64>>,
+    pub devices: Option<Vec<Device>>,
+    pub ports: Option<Vec<PortForward>>,
+    pub mounts: Option<Vec<Mount>>,
+    pub resource_limits: Option<ResourceLimits>,
+    pub backup_schedule: Option<BackupSchedule>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContainerClone {
+    pub container: ContainerId,          // Id of the new container
+    pub state: bool,                     // Also copy the state (data) of the container
+    pub ports: Option<Vec<PortForward>>, // Host ports can not be shared, so are not cloned
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportQuery {
+    pub state: Option<bool>, // Include the state (data) of the container, default false
+    pub compress: Option<bool>, // Compress the archive with zstd, default false
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportQuery {
+    pub container: Option<ContainerId>, // Id to import as, defaults to the id in the manifest
+}
+
+// manifest.json of an exported container archive
+#[derive(Serialize, Deserialize)]
+pub struct ContainerManifest {
+    pub container: ContainerId,
+    pub created: u64, // Epoch time in Seconds
+    pub host_platform: Option<String>,
+    pub state: bool,
+    pub settings: ContainerSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContainerTemplate {
+    pub name: String,
+    pub parameters: Vec<String>, // {{parameter}} placeholders in the settings, container is always provided
+    pub settings: ContainerSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateInstance {
+    pub container: ContainerId,
+    pub parameters: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -13,10 +63,106 @@ pub struct ContainerSettings {
     pub flake: String,
     pub network: Option<String>,
     pub nvidia_gpus: Option<Vec<u64>>,
+    pub devices: Option<Vec<Device>>,
+    pub ports: Option<Vec<PortForward>>,
+    pub mounts: Option<Vec<Mount>>,
+    pub resource_limits: Option<ResourceLimits>,
+    pub backup_schedule: Option<BackupSchedule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Device {
+    RenderNode(u32), // /dev/dri/renderD<N>, GPU render node (AMD, Intel, NVIDIA with nvidia-drm)
+    Kfd,             // /dev/kfd, AMD ROCm compute interface
+    Char(String),    // Any other character device, absolute path under /dev
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PortForward {
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MountSource {
+    Host(String),   // Absolute path on the host
+    Volume(String), // Named volume, shareable between containers
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub source: MountSource,
+    pub container_path: String,
+    pub read_only: bool,
+    pub idmap: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Volume {
+    pub name: String,
+    pub used_by: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ResourceLimits {
+    pub cpu_quota: Option<u32>, // Percentage of a single CPU, e.g. 200 for two full CPUs
+    pub cpu_weight: Option<u32>, // 1 - 10000
+    pub memory_max: Option<u64>, // Bytes
+    pub memory_high: Option<u64>, // Bytes
+    pub io_weight: Option<u32>, // 1 - 10000
+    pub tasks_max: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ContainerChange {
     pub settings: ContainerSettings,
     pub update_inputs: Option<Vec<String>>,
+    pub mode: Option<ChangeMode>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContainerStatus {
+    pub running: bool,
+    pub uptime: Option<u64>, // Seconds
+    pub main_pid: Option<u32>,
+    pub ip_addresses: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContainerGeneration {
+    pub number: u64,
+    pub created: u64, // Epoch time in Seconds
+    pub store_path: String,
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContainerBackup {
+    pub id: String,
+    pub created: u64, // Epoch time in Milliseconds
+    pub size: u64,    // Bytes
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum BackupFrequency {
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupSchedule {
+    // A running container is stopped while its state is archived, so each backup causes a short downtime
+    pub frequency: BackupFrequency,
+    // Retention policy, applied to all backups of the container. Unset keeps everything.
+    pub keep_last: Option<u32>,
+    pub keep_daily: Option<u32>,
+    pub keep_weekly: Option<u32>,
 }
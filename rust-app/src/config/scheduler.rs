@@ -0,0 +1,199 @@
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, write},
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    config::{
+        handlers::{
+            CONTAINER_LOCKS, create_backup, get_backup_dir, get_backup_schedule, get_backups,
+        },
+        models::{BackupFrequency, BackupSchedule},
+    },
+    request::{handlers::execute_request, models::RequestIdResult},
+    utils::{
+        env::{backupdir, containersettings},
+        scope::ContainerId,
+    },
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const HOUR: u64 = 60 * 60 * 1000;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+pub fn start() {
+    thread::spawn(|| {
+        loop {
+            run_due_backups();
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
+
+fn run_due_backups() {
+    let path = containersettings();
+    let containers: Vec<String> = match read_dir(&path) {
+        Ok(dir) => dir
+            .filter_map(|f| f.ok().and_then(|f| f.file_name().into_string().ok()))
+            .filter(|name| ContainerId::try_from(name.clone()).is_ok())
+            .collect(),
+        Err(e) => {
+            log::warn!("Could not read container dir {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    for container_id in containers {
+        let schedule = match get_backup_schedule(&container_id) {
+            Ok(Some(schedule)) => schedule,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("{}", e);
+                continue;
+            }
+        };
+        let last_backup = match get_backups(&container_id) {
+            Ok(backups) => backups.last().map(|b| b.created).unwrap_or(0),
+            Err(e) => {
+                log::warn!("{}", e);
+                continue;
+            }
+        };
+        // Failed backups are not retried until their next scheduled time, also across restarts
+        let last_attempt = read_last_attempt(&container_id);
+
+        let now = now();
+        if now < last_backup.max(last_attempt) + interval(schedule.frequency) {
+            continue;
+        }
+        if let Err(e) = write_last_attempt(&container_id, now) {
+            // Not running the backup, as it would otherwise be repeated every check
+            log::warn!("{}", e);
+            continue;
+        }
+
+        let id = container_id.clone();
+        let request_id = execute_request(Box::new(move |request_id| {
+            let _lock = CONTAINER_LOCKS.lock(id.clone());
+            let backup_id = match create_backup(&id, request_id) {
+                Ok(backup_id) => backup_id,
+                Err(e) => return e,
+            };
+
+            match prune_backups(&id, &schedule) {
+                Ok(pruned) => {
+                    if !pruned.is_empty() {
+                        log::info!("Pruned backups {:?} of container {}", pruned, id);
+                    }
+                    RequestIdResult::Success {
+                        body: Some(backup_id),
+                    }
+                }
+                Err(error) => RequestIdResult::Error { error },
+            }
+        }));
+        log::info!(
+            "Scheduled backup of container {} performed as request {}",
+            container_id,
+            request_id
+        );
+    }
+}
+
+// Stored with the backups (not parsed as a backup, as the name is not a timestamp)
+fn last_attempt_file(container_id: &str) -> PathBuf {
+    backupdir().join(container_id).join("last-attempt")
+}
+
+fn read_last_attempt(container_id: &str) -> u64 {
+    read_to_string(last_attempt_file(container_id))
+        .ok()
+        .and_then(|last_attempt| last_attempt.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn write_last_attempt(container_id: &str, timestamp: u64) -> Result<(), String> {
+    let path = last_attempt_file(container_id);
+    path.parent()
+        .map(create_dir_all)
+        .unwrap_or(Ok(()))
+        .and_then(|()| write(&path, timestamp.to_string()))
+        .map_err(|e| {
+            format!(
+                "Error writing last backup attempt {}: {}",
+                path.display(),
+                e
+            )
+        })
+}
+
+fn prune_backups(container_id: &str, schedule: &BackupSchedule) -> Result<Vec<String>, String> {
+    if schedule.keep_last.is_none()
+        && schedule.keep_daily.is_none()
+        && schedule.keep_weekly.is_none()
+    {
+        return Ok(vec![]);
+    }
+
+    let mut backups = get_backups(container_id)?;
+    backups.reverse(); // Newest first
+
+    let mut keep: HashSet<&str> = HashSet::new();
+    // Never the newest backup, so a keep count of 0 can not prune the backup just taken
+    keep.extend(backups.first().map(|b| b.id.as_str()));
+    if let Some(count) = schedule.keep_last {
+        keep.extend(backups.iter().take(count as usize).map(|b| b.id.as_str()));
+    }
+    for (count, period) in [(schedule.keep_daily, DAY), (schedule.keep_weekly, WEEK)] {
+        if let Some(count) = count {
+            // Keep the newest backup of each of the most recent periods
+            let mut periods = HashSet::new();
+            for backup in &backups {
+                if periods.len() >= count as usize {
+                    break;
+                }
+                if periods.insert(period_of(backup.created, period)) {
+                    keep.insert(&backup.id);
+                }
+            }
+        }
+    }
+
+    let mut pruned = vec![];
+    for backup in backups.iter().filter(|b| !keep.contains(b.id.as_str())) {
+        let path = get_backup_dir(container_id, &backup.id)?;
+        remove_dir_all(&path)
+            .map_err(|e| format!("Error deleting container backup {}: {}", path.display(), e))?;
+        pruned.push(backup.id.clone());
+    }
+
+    Ok(pruned)
+}
+
+fn period_of(timestamp: u64, period: u64) -> u64 {
+    if period == WEEK {
+        // Unix epoch is a thursday, shift to let weeks start on monday
+        (timestamp + 3 * DAY) / WEEK
+    } else {
+        timestamp / period
+    }
+}
+
+fn interval(frequency: BackupFrequency) -> u64 {
+    match frequency {
+        BackupFrequency::Hourly => HOUR,
+        BackupFrequency::Daily => DAY,
+        BackupFrequency::Weekly => WEEK,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
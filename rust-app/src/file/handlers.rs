@@ -1,27 +1,84 @@
 use std::{
-    fs::{self, metadata},
-    os::unix::fs::{MetadataExt, chown},
+    ffi::OsString,
+    fs::{self, Metadata},
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, lchown, symlink},
+    },
     path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
 };
 
-use actix_web::{HttpResponse, Responder, get, post, web};
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, get,
+    http::{StatusCode, header},
+    post, web,
+};
+use flate2::{Compression, read::GzEncoder};
+use futures_util::StreamExt;
+use glob::Pattern;
 use posix_acl::{ACL_EXECUTE, ACL_READ, ACL_WRITE, PosixACL, Qualifier};
 
 use crate::{
     file::models::{
-        CreateDirectory, Directory, Entity, File, GetPermissions, Permission, ReadDirectory,
-        ReadFile, RemoveDirectory, RemoveFile, SetPermissions, WriteFile,
+        ArchiveFormat, Checksum, ChecksumAlgorithm, ChecksumFile, CopyFile, CreateDirectory,
+        CreateHardlink, CreateSymlink, Directory, DirectoryEntry, DirectoryListing, Entity,
+        EntryKind, Exists, ExtractArchive, File, GetPermissions, ListDirectory, MoveFile,
+        PackDirectory, Permission, PermissionFailure, ReadDirectory, ReadFile, RemoveDirectory,
+        RemoveFile, SetPermissions, SortBy, Stat, StatFile, Upload, UploadFile, WriteFile,
+    },
+    info::handlers::{get_groups, get_users},
+    request::{
+        handlers::return_request_id,
+        models::{RequestId, RequestIdResult},
+    },
+    utils::{
+        checksum::{blake3, sha256},
+        command::{CommandExecutionMode, execute_command},
+        env::{archivelimit, containerstate, hostfileallow, hostfiledeny},
+        env::{gnutar, libarchive, zstd},
+        error::ResponseError,
+        fs::{
+            ResolvedPath, chown_entry, copy_preserving, fd_path, move_path, open_entry,
+            private_temp_dir, write_atomic,
+        },
+        lock::KeyedLocks,
+        scope::Scope,
+        stream::{command_output, receive_payload, stream_reader},
     },
-    utils::{env::containerstate, error::ResponseError},
 };
 
+// Writes to the same path wait for each other, so checking and writing can not be interleaved
+static WRITE_LOCKS: KeyedLocks<PathBuf> = KeyedLocks::new();
+// Deeper listings are cut off, so one request can't walk an entire filesystem
+const MAX_LIST_DEPTH: u32 = 8;
+// Listings stop after this many entries (before sorting and paging), marked as truncated
+const MAX_LIST_ENTRIES: usize = 10_000;
+
 #[get("/{scope}/read_file")]
-async fn read_file(path: web::Path<String>, file: web::Query<ReadFile>) -> impl Responder {
+async fn read_file(path: web::Path<Scope>, file: web::Query<ReadFile>) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &file.path);
-    match fs::read(&path) {
-        Ok(output) => HttpResponse::Ok().json(File {
+    let path = match get_path(&scope, &file.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    // Metadata of the opened file, as a concurrent write replaces the file instead of changing it
+    match path
+        .target()
+        .and_then(fs::File::open)
+        .and_then(|mut reader| {
+            let metadata = reader.metadata()?;
+            let mut output = vec![];
+            reader.read_to_end(&mut output)?;
+            Ok((output, metadata))
+        }) {
+        Ok((output, metadata)) => HttpResponse::Ok().json(File {
             content: output.into(),
+            etag: etag(&metadata),
         }),
         Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
             "Error reading file at path {}: {}",
@@ -32,24 +89,303 @@ async fn read_file(path: web::Path<String>, file: web::Query<ReadFile>) -> impl
 }
 
 #[post("/{scope}/write_file")]
-async fn write_file(path: web::Path<String>, file: web::Json<WriteFile>) -> impl Responder {
+async fn write_file(path: web::Path<Scope>, file: web::Json<WriteFile>) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &file.path);
-    match fs::write(&path, &file.content) {
-        Ok(_) => HttpResponse::Ok().finish(),
+    let path = match get_path(&scope, &file.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+
+    let file = file.into_inner();
+    let result = web::block(move || {
+        let _lock = WRITE_LOCKS.lock(path.real().to_path_buf());
+        check_unchanged(&path, &file)?;
+        write_atomic(&path.path(), &file.content).map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error writing file at path {}: {}", path.display(), e),
+            )
+        })
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    .and_then(|result| result);
+    match result {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err((status, e)) => HttpResponse::build(status).json(ResponseError::new(e)),
+    }
+}
+
+#[get("/{scope}/download")]
+async fn download(
+    request: HttpRequest,
+    path: web::Path<Scope>,
+    file: web::Query<ReadFile>,
+) -> impl Responder {
+    let scope = path.into_inner();
+    let path = match get_path(&scope, &file.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    let open_path = path.target();
+    let opened = web::block(move || {
+        let reader = fs::File::open(open_path?)?;
+        let metadata = reader.metadata()?;
+        if !metadata.is_file() {
+            return Err(Error::other("Not a file"));
+        }
+        Ok((reader, metadata.len()))
+    })
+    .await
+    .map_err(Error::other)
+    .and_then(|result| result);
+    let (mut reader, size) = match opened {
+        Ok(file) => file,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error opening file at path {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+
+    let range = match request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(|range| parse_range(range, size))
+    {
+        Some(Some(Ok(range))) => Some(range),
+        Some(Some(Err(()))) => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                .finish();
+        }
+        _ => None,
+    };
+
+    let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+    let length = if size == 0 { 0 } else { end - start + 1 };
+    let reader = match web::block(move || reader.seek(SeekFrom::Start(start)).map(|_| reader))
+        .await
+        .map_err(Error::other)
+        .and_then(|result| result)
+    {
+        Ok(reader) => reader,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error seeking file at path {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+
+    let mut response = if range.is_some() {
+        let mut response = HttpResponse::PartialContent();
+        response.insert_header((
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, size),
+        ));
+        response
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .content_type("application/octet-stream")
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .no_chunking(length)
+        .streaming(stream_reader(reader.take(length)))
+}
+
+#[get("/{scope}/upload")]
+async fn upload_status(path: web::Path<Scope>, file: web::Query<ReadFile>) -> impl Responder {
+    let scope = path.into_inner();
+    let path = match get_path(&scope, &file.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    match path.metadata() {
+        Ok(metadata) => HttpResponse::Ok().json(Upload {
+            size: metadata.len(),
+        }),
         Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
-            "Error writing file at path {}: {}",
+            "Error getting size of file at path {}: {}",
             path.display(),
             e
         ))),
     }
 }
 
+#[post("/{scope}/upload")]
+async fn upload(
+    path: web::Path<Scope>,
+    file: web::Query<UploadFile>,
+    mut payload: web::Payload,
+) -> impl Responder {
+    let scope = path.into_inner();
+    let path = match get_path(&scope, &file.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+
+    let offset = file.offset.unwrap_or(0);
+    // Held until the upload is done, so other writes to the same path can not interleave with it
+    let key = path.real().to_path_buf();
+    let _lock = match web::block(move || WRITE_LOCKS.lock(key)).await {
+        Ok(lock) => lock,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(e.to_string()));
+        }
+    };
+    // Checked before opening, so a rejected upload does not leave an empty file behind
+    let stat_path = path.path();
+    let existing = match web::block(move || match fs::symlink_metadata(&stat_path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    })
+    .await
+    .map_err(Error::other)
+    .and_then(|result| result)
+    {
+        Ok(existing) => existing,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error getting size of file at path {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    if offset > existing {
+        return HttpResponse::BadRequest().json(ResponseError::new(format!(
+            "Upload offset {} is past the end of file at path {} ({} bytes)",
+            offset,
+            path.display(),
+            existing
+        )));
+    }
+    if offset == 0 && existing > 0 && !file.truncate.unwrap_or(false) {
+        return HttpResponse::Conflict().json(ResponseError::new(format!(
+            "File at path {} already has {} bytes, set truncate to overwrite it",
+            path.display(),
+            existing
+        )));
+    }
+
+    // Anything after offset is from an interrupted chunk, which is sent again
+    let open_path = path.path();
+    let mut writer = match web::block(move || {
+        // Never through a symlink that replaced the entry after resolving it
+        let mut writer = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&open_path)?;
+        writer.set_len(offset)?;
+        writer.seek(SeekFrom::Start(offset))?;
+        Ok(writer)
+    })
+    .await
+    .map_err(Error::other)
+    .and_then(|result| result)
+    {
+        Ok(writer) => writer,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error preparing file at path {} for upload at offset {}: {}",
+                path.display(),
+                offset,
+                e
+            )));
+        }
+    };
+    while let Some(chunk) = payload.next().await {
+        let result = match chunk {
+            Ok(chunk) => web::block(move || writer.write_all(&chunk).map(|()| writer))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|result| result.map_err(|e| e.to_string())),
+            Err(e) => Err(e.to_string()),
+        };
+        writer = match result {
+            Ok(writer) => writer,
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                    "Error writing upload to file at path {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        };
+    }
+
+    let (writer, size) = match web::block(move || {
+        writer.flush()?;
+        let size = writer.metadata()?.len();
+        Ok((writer, size))
+    })
+    .await
+    .map_err(Error::other)
+    .and_then(|result| result)
+    {
+        Ok(finished) => finished,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error finishing upload to file at path {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+
+    if let Some(expected) = &file.checksum {
+        // Of the written file itself, which a new upload to the same path could replace
+        match web::block(move || sha256(&fd_path(&writer)))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result)
+        {
+            Ok(checksum) => {
+                if !checksum.eq_ignore_ascii_case(expected) {
+                    return HttpResponse::UnprocessableEntity().json(ResponseError::new(format!(
+                        "Checksum mismatch for file at path {}: expected {}, got {}",
+                        path.display(),
+                        expected,
+                        checksum
+                    )));
+                }
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(ResponseError::new(e));
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(Upload { size })
+}
+
 #[post("/{scope}/remove_file")]
-async fn remove_file(path: web::Path<String>, file: web::Json<RemoveFile>) -> impl Responder {
+async fn remove_file(path: web::Path<Scope>, file: web::Json<RemoveFile>) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &file.path);
-    match fs::remove_file(&path) {
+    let path = match get_path(&scope, &file.path, false) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    match fs::remove_file(path.path()) {
         Ok(_) => HttpResponse::Ok().finish(),
         Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
             "Error removing file at path {}: {}",
@@ -59,11 +395,331 @@ async fn remove_file(path: web::Path<String>, file: web::Json<RemoveFile>) -> im
     }
 }
 
+#[post("/{scope}/move")]
+async fn move_file(path: web::Path<Scope>, file: web::Json<MoveFile>) -> impl Responder {
+    let scope = path.into_inner();
+    let (from, to) = match get_paths(&scope, &file.from, file.to_scope.as_ref(), &file.to) {
+        Ok(paths) => paths,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    if to.real().starts_with(from.real()) {
+        return HttpResponse::BadRequest().json(ResponseError::new(format!(
+            "Can not move {} into itself ({})",
+            from.display(),
+            to.display()
+        )));
+    }
+
+    let result = web::block(move || {
+        move_path(&from.path(), &to.path())
+            .map_err(|e| format!("Error moving {} to {}: {}", from.display(), to.display(), e))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result);
+    match result {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(e)),
+    }
+}
+
+#[post("/{scope}/copy")]
+async fn copy_file(path: web::Path<Scope>, file: web::Json<CopyFile>) -> impl Responder {
+    let scope = path.into_inner();
+    let (from, to) = match get_paths(&scope, &file.from, file.to_scope.as_ref(), &file.to) {
+        Ok(paths) => paths,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    if to.real().starts_with(from.real()) {
+        return HttpResponse::BadRequest().json(ResponseError::new(format!(
+            "Can not copy {} into itself ({})",
+            from.display(),
+            to.display()
+        )));
+    }
+
+    let result = web::block(move || {
+        copy_preserving(&from.path(), &to.path()).map_err(|e| {
+            format!(
+                "Error copying {} to {}: {}",
+                from.display(),
+                to.display(),
+                e
+            )
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result);
+    match result {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(e)),
+    }
+}
+
+#[post("/{scope}/symlink")]
+async fn create_symlink(path: web::Path<Scope>, link: web::Json<CreateSymlink>) -> impl Responder {
+    let scope = path.into_inner();
+    let path = match get_path(&scope, &link.path, false) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    match symlink(&link.target, path.path()) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error creating symlink at path {} to {}: {}",
+            path.display(),
+            link.target,
+            e
+        ))),
+    }
+}
+
+#[post("/{scope}/hardlink")]
+async fn create_hardlink(
+    path: web::Path<Scope>,
+    link: web::Json<CreateHardlink>,
+) -> impl Responder {
+    let scope = path.into_inner();
+    let (target, path) = match get_paths(&scope, &link.target, None, &link.path) {
+        Ok(paths) => paths,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    match fs::hard_link(target.path(), path.path()) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error creating hardlink at path {} to {}: {}",
+            path.display(),
+            target.display(),
+            e
+        ))),
+    }
+}
+
+#[post("/{scope}/extract")]
+async fn extract(
+    path: web::Path<Scope>,
+    archive: web::Query<ExtractArchive>,
+    payload: web::Payload,
+) -> impl Responder {
+    let scope = path.into_inner();
+    let owner = match &archive.owner {
+        Some(name) => match user_id(&scope, name) {
+            Ok(Some(id)) => Some(id),
+            Ok(None) => {
+                return HttpResponse::BadRequest().json(ResponseError::new(format!(
+                    "User {} does not exist in {}",
+                    name, scope
+                )));
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(e);
+            }
+        },
+        None => None,
+    };
+    let group = match &archive.group {
+        Some(name) => match group_id(&scope, name) {
+            Ok(Some(id)) => Some(id),
+            Ok(None) => {
+                return HttpResponse::BadRequest().json(ResponseError::new(format!(
+                    "Group {} does not exist in {}",
+                    name, scope
+                )));
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(e);
+            }
+        },
+        None => None,
+    };
+
+    // Owners and setuid bits from an archive could hand out root on the host, so only kept there when asked
+    let preserve = archive
+        .preserve_owner
+        .unwrap_or(matches!(scope, Scope::Container(_)));
+
+    let destination = match create_dirs(&scope, &archive.path) {
+        Ok(destination) => destination,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(e);
+        }
+    };
+    // Inside the destination, so extracted entries are on the same filesystem and can be renamed into place
+    let work_dir = match destination.target().and_then(|dir| {
+        let work_dir = dir.join(format!(
+            ".xnode-manager-extract-{}",
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        ));
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&work_dir)
+            .map(|()| work_dir)
+    }) {
+        Ok(work_dir) => work_dir,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error creating extraction dir in {}: {}",
+                destination.display(),
+                e
+            )));
+        }
+    };
+    let archive_file = work_dir.join("archive");
+    if let Err((status, e)) = receive_payload(payload, &archive_file, archivelimit()).await {
+        let _ = fs::remove_dir_all(&work_dir);
+        return HttpResponse::build(status).json(ResponseError::new(format!(
+            "Error receiving archive: {}",
+            e
+        )));
+    }
+
+    return_request_id(Box::new(move |request_id| {
+        // Extracted separately first, so entries of the archive can not write through existing symlinks
+        let result = extract_archive(
+            &archive_file,
+            &work_dir.join("staging"),
+            &destination,
+            preserve,
+            owner,
+            group,
+            request_id,
+        );
+        if let Err(e) = fs::remove_dir_all(&work_dir) {
+            log::warn!(
+                "Could not remove extraction dir in {}: {}",
+                destination.display(),
+                e
+            );
+        }
+
+        result
+    }))
+}
+
+#[get("/{scope}/pack")]
+async fn pack(path: web::Path<Scope>, dir: web::Query<PackDirectory>) -> impl Responder {
+    let scope = path.into_inner();
+    let path = match get_path(&scope, &dir.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    let target = match path.target() {
+        Ok(target) => target,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error opening directory at path {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let format = dir.format.as_ref().unwrap_or(&ArchiveFormat::Tar);
+
+    let mut command = Command::new(format!("{}tar", gnutar()));
+    command
+        .arg("--create")
+        .arg("--file=-")
+        .arg("--acls")
+        .arg("--xattrs");
+    if let ArchiveFormat::TarZst = format {
+        command.arg(format!("--use-compress-program={}zstd", zstd()));
+    }
+
+    // Owner names are taken from the users and groups of the scope, instead of the host
+    let maps_dir = match private_temp_dir("pack") {
+        Ok(maps_dir) => maps_dir,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error creating owner map dir: {}",
+                e
+            )));
+        }
+    };
+    if let Some(prefix) = scope_root(&scope) {
+        let owner_map = maps_dir.join("owners");
+        let group_map = maps_dir.join("groups");
+        if let Err(e) = write_owner_maps(prefix, &owner_map, &group_map) {
+            let _ = fs::remove_dir_all(&maps_dir);
+            return HttpResponse::InternalServerError().json(e);
+        }
+        command
+            .arg(format!("--owner-map={}", owner_map.display()))
+            .arg(format!("--group-map={}", group_map.display()));
+    }
+    command.arg("--directory").arg(&target).arg(".");
+
+    let display = path.display().to_string();
+    let name = path
+        .real()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or("root".to_string());
+    let cleanup_dir = maps_dir.clone();
+    let on_finish = Box::new(move || {
+        // The directory stays opened for tar until it is done
+        drop(path);
+        if let Err(e) = fs::remove_dir_all(&cleanup_dir)
+            && e.kind() != ErrorKind::NotFound
+        {
+            log::warn!(
+                "Could not remove owner map dir {}: {}",
+                cleanup_dir.display(),
+                e
+            );
+        }
+    });
+
+    let output = match command_output(command, on_finish) {
+        Ok(output) => output,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error packing directory at path {}: {}",
+                display, e
+            )));
+        }
+    };
+    let (body, content_type, extension): (Box<dyn Read + Send>, &str, &str) = match format {
+        ArchiveFormat::Tar => (Box::new(output), "application/x-tar", "tar"),
+        ArchiveFormat::TarGz => (
+            Box::new(GzEncoder::new(output, Compression::default())),
+            "application/gzip",
+            "tar.gz",
+        ),
+        ArchiveFormat::TarZst => (Box::new(output), "application/zstd", "tar.zst"),
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.{}\"", name, extension),
+        ))
+        .streaming(stream_reader(body))
+}
+
 #[get("/{scope}/read_directory")]
-async fn read_directory(path: web::Path<String>, dir: web::Query<ReadDirectory>) -> impl Responder {
+async fn read_directory(path: web::Path<Scope>, dir: web::Query<ReadDirectory>) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &dir.path);
-    match fs::read_dir(&path) {
+    let path = match get_path(&scope, &dir.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    match path.target().and_then(fs::read_dir) {
         Ok(content) => {
             let content_with_type = content
                 .flat_map(|c| c.ok())
@@ -119,19 +775,97 @@ async fn read_directory(path: web::Path<String>, dir: web::Query<ReadDirectory>)
     }
 }
 
+#[get("/{scope}/list_directory")]
+async fn list_directory(path: web::Path<Scope>, dir: web::Query<ListDirectory>) -> impl Responder {
+    let scope = path.into_inner();
+    let path = match get_path(&scope, &dir.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    let pattern = match dir.glob.as_deref().map(Pattern::new).transpose() {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(ResponseError::new(format!(
+                "Invalid glob pattern {}: {}",
+                dir.glob.as_deref().unwrap_or_default(),
+                e
+            )));
+        }
+    };
+
+    let depth = dir.depth.unwrap_or(1).min(MAX_LIST_DEPTH);
+    let display = path.display().to_string();
+    let mut entries = match web::block(move || {
+        let mut entries = vec![];
+        list_entries(
+            &path.target()?,
+            Path::new(""),
+            depth,
+            pattern.as_ref(),
+            &mut entries,
+        )
+        .map(|()| entries)
+    })
+    .await
+    .map_err(Error::other)
+    .and_then(|result| result)
+    {
+        Ok(entries) => entries,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error reading directory at path {}: {}",
+                display, e
+            )));
+        }
+    };
+    // One more than the cap is collected, to know whether anything was left out
+    let truncated = entries.len() > MAX_LIST_ENTRIES;
+    entries.truncate(MAX_LIST_ENTRIES);
+
+    match dir.sort.as_ref().unwrap_or(&SortBy::Name) {
+        SortBy::Name => entries.sort_by(|(a, _), (b, _)| a.cmp(b)),
+        SortBy::Size => entries.sort_by_key(|(_, entry)| entry.size),
+        SortBy::Modified => entries.sort_by_key(|(_, entry)| entry.modified),
+    }
+    if dir.descending.unwrap_or(false) {
+        entries.reverse();
+    }
+
+    let total = entries.len();
+    HttpResponse::Ok().json(DirectoryListing {
+        entries: entries
+            .into_iter()
+            .skip(dir.offset.unwrap_or(0))
+            .take(dir.limit.unwrap_or(usize::MAX))
+            .map(|(_, entry)| entry)
+            .collect(),
+        total,
+        truncated,
+    })
+}
+
 #[post("/{scope}/create_directory")]
 async fn create_directory(
-    path: web::Path<String>,
+    path: web::Path<Scope>,
     dir: web::Json<CreateDirectory>,
 ) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &dir.path);
-    let create = if dir.make_parent {
-        fs::create_dir_all(&path)
-    } else {
-        fs::create_dir(&path)
+    if dir.make_parent {
+        return match create_dirs(&scope, &dir.path) {
+            Ok(_) => HttpResponse::Ok().finish(),
+            Err(e) => HttpResponse::InternalServerError().json(e),
+        };
+    }
+
+    let path = match get_path(&scope, &dir.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
     };
-    match create {
+    match fs::create_dir(path.path()) {
         Ok(_) => HttpResponse::Ok().finish(),
         Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
             "Error creating directory at path {}: {}",
@@ -143,15 +877,20 @@ async fn create_directory(
 
 #[post("/{scope}/remove_directory")]
 async fn remove_directory(
-    path: web::Path<String>,
+    path: web::Path<Scope>,
     dir: web::Json<RemoveDirectory>,
 ) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &dir.path);
+    let path = match get_path(&scope, &dir.path, false) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
     let create = if dir.make_empty {
-        fs::remove_dir_all(&path)
+        fs::remove_dir_all(path.path())
     } else {
-        fs::remove_dir(&path)
+        fs::remove_dir(path.path())
     };
     match create {
         Ok(_) => HttpResponse::Ok().finish(),
@@ -165,12 +904,17 @@ async fn remove_directory(
 
 #[get("/{scope}/get_permissions")]
 async fn get_permissions(
-    path: web::Path<String>,
+    path: web::Path<Scope>,
     target: web::Query<GetPermissions>,
 ) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &target.path);
-    let (owner_user, owner_group) = match metadata(&path) {
+    let path = match get_path(&scope, &target.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    let (owner_user, owner_group) = match path.metadata() {
         Ok(metadata) => (metadata.uid(), metadata.gid()),
         Err(e) => {
             return HttpResponse::InternalServerError().json(ResponseError::new(format!(
@@ -180,27 +924,12 @@ async fn get_permissions(
             )));
         }
     };
-    match PosixACL::read_acl(&path) {
-        Ok(permissions) => HttpResponse::Ok().json(
-            permissions
-                .entries()
-                .into_iter()
-                .filter(|permission| !matches!(permission.qual, Qualifier::Mask))
-                .map(|permission| Permission {
-                    granted_to: match permission.qual {
-                        Qualifier::UserObj => Entity::User(owner_user),
-                        Qualifier::GroupObj => Entity::Group(owner_group),
-                        Qualifier::Other => Entity::Any,
-                        Qualifier::User(id) => Entity::User(id),
-                        Qualifier::Group(id) => Entity::Group(id),
-                        _ => Entity::Unknown,
-                    },
-                    read: permission.perm & ACL_READ != 0,
-                    write: permission.perm & ACL_WRITE != 0,
-                    execute: permission.perm & ACL_EXECUTE != 0,
-                })
-                .collect::<Vec<Permission>>(),
-        ),
+    match path
+        .target()
+        .map_err(|e| e.to_string())
+        .and_then(|target| PosixACL::read_acl(target).map_err(|e| e.to_string()))
+    {
+        Ok(acl) => HttpResponse::Ok().json(acl_permissions(&acl, owner_user, owner_group)),
         Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
             "Error getting permissions on path {}: {}",
             path.display(),
@@ -209,57 +938,530 @@ async fn get_permissions(
     }
 }
 
+#[get("/{scope}/exists")]
+async fn exists(path: web::Path<Scope>, file: web::Query<StatFile>) -> impl Responder {
+    let scope = path.into_inner();
+    let follow = file.follow.unwrap_or(true);
+    let path = match get_path(&scope, &file.path, follow) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    match path.metadata() {
+        Ok(_) => HttpResponse::Ok().json(Exists { exists: true }),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            HttpResponse::Ok().json(Exists { exists: false })
+        }
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error checking existence of path {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+#[get("/{scope}/stat")]
+async fn stat(path: web::Path<Scope>, file: web::Query<StatFile>) -> impl Responder {
+    let scope = path.into_inner();
+    let follow = file.follow.unwrap_or(true);
+    let path = match get_path(&scope, &file.path, follow) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return HttpResponse::NotFound().json(ResponseError::new(format!(
+                "Path {} does not exist",
+                path.display()
+            )));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                "Error getting metadata of path {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+
+    let file_type = metadata.file_type();
+    let permissions = if file_type.is_symlink() {
+        None
+    } else {
+        match path
+            .target()
+            .map_err(|e| e.to_string())
+            .and_then(|target| PosixACL::read_acl(target).map_err(|e| e.to_string()))
+        {
+            Ok(acl) => Some(acl_permissions(&acl, metadata.uid(), metadata.gid())),
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(ResponseError::new(format!(
+                    "Error getting permissions on path {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+    };
+    let prefix = scope_root(&scope);
+    HttpResponse::Ok().json(Stat {
+        kind: if file_type.is_dir() {
+            EntryKind::Directory
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::Unknown
+        },
+        size: metadata.len(),
+        accessed: metadata.atime(),
+        modified: metadata.mtime(),
+        changed: metadata.ctime(),
+        created: metadata
+            .created()
+            .ok()
+            .and_then(|created| created.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|created| created.as_secs() as i64),
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        owner: get_users(prefix.clone()).ok().and_then(|users| {
+            users
+                .into_iter()
+                .find_map(|user| (user.id == metadata.uid()).then_some(user.name))
+        }),
+        group: get_groups(prefix).ok().and_then(|groups| {
+            groups
+                .into_iter()
+                .find_map(|group| (group.id == metadata.gid()).then_some(group.name))
+        }),
+        symlink_target: file_type
+            .is_symlink()
+            .then(|| fs::read_link(path.path()).ok())
+            .flatten()
+            .map(|target| target.into_os_string().into_vec().into()),
+        permissions,
+    })
+}
+
+#[get("/{scope}/checksum")]
+async fn get_checksum(path: web::Path<Scope>, file: web::Query<ChecksumFile>) -> impl Responder {
+    let scope = path.into_inner();
+    let path = match get_path(&scope, &file.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+    let algorithm = file
+        .into_inner()
+        .algorithm
+        .unwrap_or(ChecksumAlgorithm::Sha256);
+    let result = web::block(move || {
+        let target = path
+            .target()
+            .map_err(|e| format!("Error opening file at path {}: {}", path.display(), e))?;
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => sha256(&target),
+            ChecksumAlgorithm::Blake3 => blake3(&target),
+        }
+        .map(|checksum| (algorithm, checksum))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result);
+    match result {
+        Ok((algorithm, checksum)) => HttpResponse::Ok().json(Checksum {
+            algorithm,
+            checksum,
+        }),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(e)),
+    }
+}
+
 #[post("/{scope}/set_permissions")]
 async fn set_permissions(
-    path: web::Path<String>,
+    path: web::Path<Scope>,
     target: web::Json<SetPermissions>,
 ) -> impl Responder {
     let scope = path.into_inner();
-    let path = get_path(&scope, &target.path);
-
-    let owner_user =
-        match target
-            .permissions
-            .iter()
-            .find_map(|permission| match permission.granted_to {
-                Entity::User(id) => Some(id),
-                _ => None,
-            }) {
-            Some(id) => id,
-            None => {
-                return HttpResponse::InternalServerError().json(ResponseError::new(
-                    "No user permission (one is required).".to_string(),
-                ));
+    let path = match get_path(&scope, &target.path, true) {
+        Ok(path) => path,
+        Err(e) => {
+            return HttpResponse::Forbidden().json(e);
+        }
+    };
+
+    let owner = match &target.owner {
+        Some(name) => match user_id(&scope, name) {
+            Ok(Some(id)) => Some(id),
+            Ok(None) => {
+                return HttpResponse::BadRequest().json(ResponseError::new(format!(
+                    "User {} does not exist in {}",
+                    name, scope
+                )));
             }
-        };
-    let owner_group =
-        match target
-            .permissions
-            .iter()
-            .find_map(|permission| match permission.granted_to {
-                Entity::Group(id) => Some(id),
-                _ => None,
-            }) {
-            Some(id) => id,
-            None => {
-                return HttpResponse::InternalServerError().json(ResponseError::new(
-                    "No group permission (one is required).".to_string(),
-                ));
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(e);
             }
-        };
+        },
+        None => None,
+    };
+    let group = match &target.group {
+        Some(name) => match group_id(&scope, name) {
+            Ok(Some(id)) => Some(id),
+            Ok(None) => {
+                return HttpResponse::BadRequest().json(ResponseError::new(format!(
+                    "Group {} does not exist in {}",
+                    name, scope
+                )));
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(e);
+            }
+        },
+        None => None,
+    };
 
-    if let Err(e) = chown(&path, Some(owner_user), Some(owner_group)) {
-        return HttpResponse::InternalServerError().json(ResponseError::new(format!(
-            "Error setting owner {}:{} on path {}: {}",
-            owner_user,
-            owner_group,
-            path.display(),
-            e
-        )));
+    let target = target.into_inner();
+    let result = web::block(move || {
+        let mut failures = vec![];
+        match path.entry() {
+            Ok(entry) => apply_permissions(
+                &entry,
+                Path::new(&target.path),
+                &target,
+                owner,
+                group,
+                &mut failures,
+            ),
+            Err(e) => failures.push(PermissionFailure {
+                path: target.path.clone(),
+                error: format!("Error opening: {}", e),
+            }),
+        }
+        failures
+    })
+    .await;
+    match result {
+        Ok(failures) if failures.is_empty() => HttpResponse::Ok().finish(),
+        Ok(failures) => HttpResponse::InternalServerError().json(failures),
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(e.to_string())),
+    }
+}
+
+// Conflict if the file no longer matches what the client expects it to be
+// Looked up again by name, as a write that held the lock before may have replaced the resolved file
+fn check_unchanged(path: &ResolvedPath, file: &WriteFile) -> Result<(), (StatusCode, String)> {
+    if file.expected_etag.is_none()
+        && file.expected_modified.is_none()
+        && file.expected_checksum.is_none()
+    {
+        return Ok(());
+    }
+
+    let current = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path.path());
+    let (current, metadata) = match current.and_then(|current| {
+        let metadata = current.metadata()?;
+        Ok((current, metadata))
+    }) {
+        Ok((current, metadata)) => (current, metadata),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("File at path {} no longer exists", path.display()),
+            ));
+        }
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!(
+                    "Error getting metadata of file at path {}: {}",
+                    path.display(),
+                    e
+                ),
+            ));
+        }
+    };
+    if let Some(expected) = &file.expected_etag
+        && expected != &etag(&metadata)
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "File at path {} changed (etag {} instead of {})",
+                path.display(),
+                etag(&metadata),
+                expected
+            ),
+        ));
+    }
+    if let Some(expected) = file.expected_modified
+        && expected != metadata.mtime()
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "File at path {} changed (modified at {} instead of {})",
+                path.display(),
+                metadata.mtime(),
+                expected
+            ),
+        ));
+    }
+    if let Some(expected) = &file.expected_checksum {
+        let checksum =
+            sha256(&fd_path(&current)).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        if !checksum.eq_ignore_ascii_case(expected) {
+            return Err((
+                StatusCode::CONFLICT,
+                format!(
+                    "File at path {} changed (checksum {} instead of {})",
+                    path.display(),
+                    checksum,
+                    expected
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Paths of a container are kept inside its root, host paths need to be allowed and not denied
+fn get_path(scope: &Scope, path: &str, follow_last: bool) -> Result<ResolvedPath, ResponseError> {
+    match scope {
+        Scope::Container(container_id) => {
+            let root = containerstate().join(container_id);
+            ResolvedPath::new(&root, Path::new(path), follow_last).map_err(|e| {
+                ResponseError::new(format!(
+                    "Error resolving path {} in container {}: {}",
+                    path, container_id, e
+                ))
+            })
+        }
+        Scope::Host => {
+            let resolved = ResolvedPath::new(Path::new("/"), Path::new(path), follow_last)
+                .map_err(|e| ResponseError::new(format!("Error resolving path {}: {}", path, e)))?;
+            let canonical = |dir: &PathBuf| dir.canonicalize().unwrap_or(dir.to_path_buf());
+            if !hostfileallow()
+                .iter()
+                .any(|dir| resolved.real().starts_with(canonical(dir)))
+                || hostfiledeny()
+                    .iter()
+                    .any(|dir| resolved.real().starts_with(canonical(dir)))
+            {
+                return Err(ResponseError::new(format!(
+                    "Access to path {} is not allowed",
+                    resolved.display()
+                )));
+            }
+
+            Ok(resolved)
+        }
+    }
+}
+
+// Source and destination of an operation, which do not follow a symlink as last component
+fn get_paths(
+    scope: &Scope,
+    from: &str,
+    to_scope: Option<&Scope>,
+    to: &str,
+) -> Result<(ResolvedPath, ResolvedPath), ResponseError> {
+    Ok((
+        get_path(scope, from, false)?,
+        get_path(to_scope.unwrap_or(scope), to, false)?,
+    ))
+}
+
+// Create path and its missing parents one at a time, each resolved inside the scope like get_path
+fn create_dirs(scope: &Scope, path: &str) -> Result<ResolvedPath, ResponseError> {
+    let root = scope_root(scope).unwrap_or(PathBuf::from("/"));
+    let mut current = PathBuf::new();
+    for component in Path::new(path).components() {
+        current.push(component);
+        // Existing parents only need to be allowed if they are the requested path itself
+        if ResolvedPath::new(&root, &current, true).is_ok_and(|dir| dir.metadata().is_ok()) {
+            continue;
+        }
+
+        let dir = get_path(scope, &current.to_string_lossy(), true)?;
+        match fs::create_dir(dir.path()) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+                return Err(ResponseError::new(format!(
+                    "Error creating directory at path {}: {}",
+                    dir.display(),
+                    e
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    get_path(scope, path, true)
+}
+
+// Changes on every write, as writes replace the file (new inode) and update the modification time
+fn etag(metadata: &Metadata) -> String {
+    format!(
+        "{:x}-{:x}.{:x}-{:x}",
+        metadata.ino(),
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.len()
+    )
+}
+
+fn acl_permissions(acl: &PosixACL, owner_user: u32, owner_group: u32) -> Vec<Permission> {
+    acl.entries()
+        .into_iter()
+        .filter(|permission| !matches!(permission.qual, Qualifier::Mask))
+        .map(|permission| Permission {
+            granted_to: match permission.qual {
+                Qualifier::UserObj => Entity::User(owner_user),
+                Qualifier::GroupObj => Entity::Group(owner_group),
+                Qualifier::Other => Entity::Any,
+                Qualifier::User(id) => Entity::User(id),
+                Qualifier::Group(id) => Entity::Group(id),
+                _ => Entity::Unknown,
+            },
+            read: permission.perm & ACL_READ != 0,
+            write: permission.perm & ACL_WRITE != 0,
+            execute: permission.perm & ACL_EXECUTE != 0,
+        })
+        .collect()
+}
+
+// Root of the scope as prefix for its users and groups, None for the host
+fn scope_root(scope: &Scope) -> Option<PathBuf> {
+    match scope {
+        Scope::Container(container_id) => Some(containerstate().join(container_id)),
+        Scope::Host => None,
     }
+}
+
+// Id of a user name in the scope, names that do not exist can also be given as id
+fn user_id(scope: &Scope, name: &str) -> Result<Option<u32>, ResponseError> {
+    get_users(scope_root(scope)).map(|users| {
+        users
+            .into_iter()
+            .find_map(|user| (user.name == name).then_some(user.id))
+            .or(name.parse().ok())
+    })
+}
+
+fn group_id(scope: &Scope, name: &str) -> Result<Option<u32>, ResponseError> {
+    get_groups(scope_root(scope)).map(|groups| {
+        groups
+            .into_iter()
+            .find_map(|group| (group.name == name).then_some(group.id))
+            .or(name.parse().ok())
+    })
+}
+
+// Set owner and ACLs on the opened entry (and everything below it if recursive), collecting failures instead of stopping
+// Entries below it are opened relative to their parent, so replacing a directory with a symlink meanwhile has no effect
+fn apply_permissions(
+    entry: &fs::File,
+    display_path: &Path,
+    target: &SetPermissions,
+    owner: Option<u32>,
+    group: Option<u32>,
+    failures: &mut Vec<PermissionFailure>,
+) {
+    let mut fail = |error: String| {
+        failures.push(PermissionFailure {
+            path: display_path.to_string_lossy().to_string(),
+            error,
+        })
+    };
+
+    // Symlinks have no permissions of their own and are not followed (the given path itself is already resolved)
+    let metadata = match entry.metadata() {
+        Ok(metadata) if metadata.is_symlink() => {
+            return;
+        }
+        Ok(metadata) => metadata,
+        Err(e) => {
+            fail(format!("Error getting metadata: {}", e));
+            return;
+        }
+    };
+
+    if (owner.is_some() || group.is_some())
+        && let Err(e) = chown_entry(entry, owner, group)
+    {
+        fail(format!("Error setting owner: {}", e));
+    }
+    let owner_user = owner.unwrap_or(metadata.uid());
+    let owner_group = group.unwrap_or(metadata.gid());
 
+    let path = fd_path(entry);
+    let recursive = target.recursive.unwrap_or(false);
+    if let Some(permissions) = &target.permissions {
+        // As chmod X: recursively, execute is only granted on directories and files that are already executable
+        let execute = !recursive || metadata.is_dir() || metadata.mode() & 0o111 != 0;
+        if let Err(e) = build_acl(permissions, owner_user, owner_group, execute).write_acl(&path) {
+            fail(format!("Error setting permissions: {}", e));
+        }
+    }
+    if metadata.is_dir()
+        && let Some(default_permissions) = &target.default_permissions
+        && let Err(e) =
+            build_acl(default_permissions, owner_user, owner_group, true).write_default_acl(&path)
+    {
+        fail(format!("Error setting default permissions: {}", e));
+    }
+
+    if metadata.is_dir() && recursive {
+        match fs::read_dir(&path) {
+            Ok(entries) => {
+                for name in entries
+                    .flat_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name())
+                {
+                    match open_entry(Some(entry), Path::new(&name)) {
+                        Ok(child) => apply_permissions(
+                            &child,
+                            &display_path.join(&name),
+                            target,
+                            owner,
+                            group,
+                            failures,
+                        ),
+                        Err(e) => failures.push(PermissionFailure {
+                            path: display_path.join(&name).to_string_lossy().to_string(),
+                            error: format!("Error opening: {}", e),
+                        }),
+                    }
+                }
+            }
+            Err(e) => failures.push(PermissionFailure {
+                path: display_path.to_string_lossy().to_string(),
+                error: format!("Error reading directory: {}", e),
+            }),
+        }
+    }
+}
+
+// Entries of the owner user and group are the owner entries of the ACL, execute is left out if not allowed
+fn build_acl(
+    permissions: &[Permission],
+    owner_user: u32,
+    owner_group: u32,
+    allow_execute: bool,
+) -> PosixACL {
     let mut acl = PosixACL::empty();
-    for permission in &target.permissions {
+    for permission in permissions {
         let mut perm = 0;
         if permission.read {
             perm |= ACL_READ;
@@ -267,7 +1469,7 @@ async fn set_permissions(
         if permission.write {
             perm |= ACL_WRITE;
         }
-        if permission.execute {
+        if permission.execute && allow_execute {
             perm |= ACL_EXECUTE;
         }
         match permission.granted_to {
@@ -291,34 +1493,242 @@ async fn set_permissions(
             Entity::Unknown => {}
         };
     }
-    match acl.write_acl(&path) {
-        Ok(_) => HttpResponse::Ok().finish(),
-        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
-            "Error setting permissions on path {}: {}",
-            path.display(),
-            e
-        ))),
-    }
+
+    acl
+}
+
+fn write_owner_maps(
+    prefix: PathBuf,
+    owner_map: &Path,
+    group_map: &Path,
+) -> Result<(), ResponseError> {
+    let owners: String = get_users(Some(prefix.clone()))?
+        .into_iter()
+        .map(|user| format!("+{} {}:{}\n", user.id, user.name, user.id))
+        .collect();
+    let groups: String = get_groups(Some(prefix))?
+        .into_iter()
+        .map(|group| format!("+{} {}:{}\n", group.id, group.name, group.id))
+        .collect();
+    owner_map
+        .parent()
+        .map(fs::create_dir_all)
+        .unwrap_or(Ok(()))
+        .and_then(|()| fs::write(owner_map, owners))
+        .and_then(|()| fs::write(group_map, groups))
+        .map_err(|e| ResponseError::new(format!("Error writing owner maps: {}", e)))
 }
 
-fn get_path(scope: &str, path_from_root: &str) -> PathBuf {
-    if scope.starts_with("container:") {
-        containerstate()
-            .join(scope.replace("container:", ""))
-            .join(remove_first_slash(path_from_root))
+fn extract_archive(
+    archive: &Path,
+    staging: &Path,
+    destination: &ResolvedPath,
+    preserve: bool,
+    owner: Option<u32>,
+    group: Option<u32>,
+    request_id: RequestId,
+) -> RequestIdResult {
+    let dir = match destination.target() {
+        Ok(dir) => dir,
+        Err(e) => {
+            return RequestIdResult::Error {
+                error: format!("Error opening {}: {}", destination.display(), e),
+            };
+        }
+    };
+    if let Err(e) = fs::DirBuilder::new().mode(0o700).create(staging) {
+        return RequestIdResult::Error {
+            error: format!(
+                "Error creating extraction dir in {}: {}",
+                destination.display(),
+                e
+            ),
+        };
+    }
+
+    // bsdtar detects the format (tar, compressed tar or zip) itself
+    let mut command = Command::new(format!("{}bsdtar", libarchive()));
+    command.arg("--extract").arg("--file").arg(archive);
+    if preserve {
+        command
+            .arg("--acls")
+            .arg("--xattrs")
+            .arg("--numeric-owner")
+            .arg("--same-owner")
+            .arg("--preserve-permissions");
     } else {
-        Path::new(path_from_root).to_path_buf()
+        // bsdtar keeps all of these by default when running as root
+        command
+            .arg("--no-acls")
+            .arg("--no-xattrs")
+            .arg("--no-same-owner")
+            .arg("--no-same-permissions");
+    }
+    command.arg("--directory").arg(staging);
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+        return RequestIdResult::Error {
+            error: format!("Error extracting archive: {}", e),
+        };
+    }
+
+    if (owner.is_some() || group.is_some())
+        && let Err(e) = chown_all(staging, owner, group)
+    {
+        return RequestIdResult::Error {
+            error: format!("Error setting owner of extracted entries: {}", e),
+        };
+    }
+
+    let entries: Vec<OsString> = match fs::read_dir(staging) {
+        Ok(entries) => entries
+            .flat_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .collect(),
+        Err(e) => {
+            return RequestIdResult::Error {
+                error: format!("Error reading extracted entries: {}", e),
+            };
+        }
+    };
+    let existing: Vec<String> = entries
+        .iter()
+        .filter(|name| fs::symlink_metadata(dir.join(name)).is_ok())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect();
+    if !existing.is_empty() {
+        return RequestIdResult::Error {
+            error: format!(
+                "Entries already exist in {}: {}",
+                destination.display(),
+                existing.join(", ")
+            ),
+        };
+    }
+
+    for name in entries {
+        if let Err(e) = move_path(&staging.join(&name), &dir.join(&name)) {
+            return RequestIdResult::Error {
+                error: format!(
+                    "Error moving extracted {} to {}: {}",
+                    name.to_string_lossy(),
+                    destination.display(),
+                    e
+                ),
+            };
+        }
     }
+
+    RequestIdResult::Success { body: None }
+}
+
+fn chown_all(path: &Path, owner: Option<u32>, group: Option<u32>) -> Result<(), Error> {
+    lchown(path, owner, group)?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_all(&entry?.path(), owner, group)?;
+        }
+    }
+
+    Ok(())
 }
 
-fn remove_first_slash(string: &str) -> &str {
-    let mut chars = string.chars();
+// Collect the entries of dir (and its subdirectories up to depth) with their relative path to sort by
+// Stops once it has more than MAX_LIST_ENTRIES
+fn list_entries(
+    dir: &Path,
+    relative: &Path,
+    depth: u32,
+    pattern: Option<&Pattern>,
+    entries: &mut Vec<(PathBuf, DirectoryEntry)>,
+) -> Result<(), Error> {
+    if depth == 0 {
+        return Ok(());
+    }
 
-    if let Some(char) = chars.next() {
-        if char != '/' {
-            return string;
+    for entry in fs::read_dir(dir)?.flat_map(|entry| entry.ok()) {
+        if entries.len() > MAX_LIST_ENTRIES {
+            break;
         }
+
+        let name = entry.file_name();
+        let path = relative.join(&name);
+        // Symlinks are not followed, their target is returned instead
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                continue;
+            }
+        };
+
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            // Subdirectories that can not be read are still listed themselves
+            let _ = list_entries(&entry.path(), &path, depth - 1, pattern, entries);
+        }
+
+        if pattern.is_some_and(|pattern| !pattern.matches(&name.to_string_lossy())) {
+            continue;
+        }
+
+        entries.push((
+            path.clone(),
+            DirectoryEntry {
+                name: name.as_bytes().to_vec().into(),
+                path: path.as_os_str().as_bytes().to_vec().into(),
+                kind: if file_type.is_dir() {
+                    EntryKind::Directory
+                } else if file_type.is_file() {
+                    EntryKind::File
+                } else if file_type.is_symlink() {
+                    EntryKind::Symlink
+                } else {
+                    EntryKind::Unknown
+                },
+                size: metadata.len(),
+                modified: metadata.mtime(),
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                symlink_target: file_type
+                    .is_symlink()
+                    .then(|| fs::read_link(entry.path()).ok())
+                    .flatten()
+                    .map(|target| target.into_os_string().into_vec().into()),
+                inode: metadata.ino(),
+                hardlinks: metadata.nlink(),
+            },
+        ));
+    }
+
+    Ok(())
+}
+
+// Single range of a Range header as (start, end) inclusive, None to ignore the header
+fn parse_range(range: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let range = range.strip_prefix("bytes=")?;
+    if range.contains(",") {
+        // Multiple ranges are not supported, the full file is sent instead
+        return None;
+    }
+
+    let (start, end) = range.split_once("-")?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse::<u64>().ok()?, size.checked_sub(1)?),
+        (start, end) => (
+            start.parse::<u64>().ok()?,
+            end.parse::<u64>().ok()?.min(size.checked_sub(1)?),
+        ),
+    };
+    if start > end || start >= size {
+        return Some(Err(()));
     }
 
-    chars.as_str()
+    Some(Ok((start, end)))
 }
@@ -10,10 +10,23 @@ pub fn scope() -> String {
 pub fn configure(cfg: &mut ServiceConfig) {
     cfg.service(handlers::read_file);
     cfg.service(handlers::write_file);
+    cfg.service(handlers::download);
+    cfg.service(handlers::upload_status);
+    cfg.service(handlers::upload);
     cfg.service(handlers::remove_file);
+    cfg.service(handlers::move_file);
+    cfg.service(handlers::copy_file);
+    cfg.service(handlers::create_symlink);
+    cfg.service(handlers::create_hardlink);
+    cfg.service(handlers::extract);
+    cfg.service(handlers::pack);
     cfg.service(handlers::read_directory);
+    cfg.service(handlers::list_directory);
     cfg.service(handlers::create_directory);
     cfg.service(handlers::remove_directory);
     cfg.service(handlers::get_permissions);
+    cfg.service(handlers::exists);
+    cfg.service(handlers::stat);
+    cfg.service(handlers::get_checksum);
     cfg.service(handlers::set_permissions);
 }
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::utils::output::Output;
+use crate::utils::{output::Output, scope::Scope};
 
 #[derive(Serialize, Deserialize)]
 pub struct ReadFile {
@@ -11,6 +11,23 @@ pub struct ReadFile {
 pub struct WriteFile {
     pub path: String,
     pub content: Vec<u8>,
+    // Only write if the file is unchanged since it was read, otherwise the write fails with 409
+    pub expected_etag: Option<String>,
+    pub expected_modified: Option<i64>,
+    pub expected_checksum: Option<String>, // sha256
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadFile {
+    pub path: String,
+    pub offset: Option<u64>, // Byte to continue an upload at, 0 (new upload) if not set
+    pub checksum: Option<String>, // sha256 of the complete file, verified after writing the chunk
+    pub truncate: Option<bool>, // Allow offset 0 to overwrite an existing non-empty file, false if not set
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Upload {
+    pub size: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -18,11 +35,94 @@ pub struct RemoveFile {
     pub path: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct MoveFile {
+    pub from: String,
+    pub to: String,
+    pub to_scope: Option<Scope>, // Scope of to, same as from if not set
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CopyFile {
+    pub from: String,
+    pub to: String,
+    pub to_scope: Option<Scope>, // Scope of to, same as from if not set
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateSymlink {
+    pub path: String,
+    pub target: String, // Stored as is, resolved inside the scope when followed
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateHardlink {
+    pub path: String,
+    pub target: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatFile {
+    pub path: String,
+    pub follow: Option<bool>, // Follow a symlink as last component, true if not set
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChecksumFile {
+    pub path: String,
+    pub algorithm: Option<ChecksumAlgorithm>, // Sha256 if not set
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExtractArchive {
+    pub path: String,
+    pub owner: Option<String>, // User name in the scope to own all extracted entries, as in the archive (or root without preserve_owner) if not set
+    pub group: Option<String>, // Group name in the scope to own all extracted entries, as in the archive (or root without preserve_owner) if not set
+    pub preserve_owner: Option<bool>, // Keep owners, permissions (including setuid), ACLs and xattrs from the archive, only for containers if not set
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PackDirectory {
+    pub path: String,
+    pub format: Option<ArchiveFormat>, // Tar if not set
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ReadDirectory {
     pub path: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ListDirectory {
+    pub path: String,
+    pub depth: Option<u32>, // Directory levels to list (at most 8), 1 (only direct entries) if not set
+    pub sort: Option<SortBy>,
+    pub descending: Option<bool>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    pub glob: Option<String>, // Only include entries whose name matches, all directories are still descended into
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum SortBy {
+    Name,
+    Size,
+    Modified,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateDirectory {
     pub path: String,
@@ -43,12 +143,17 @@ pub struct GetPermissions {
 #[derive(Serialize, Deserialize)]
 pub struct SetPermissions {
     pub path: String,
-    pub permissions: Vec<Permission>,
+    pub permissions: Option<Vec<Permission>>, // Permissions are not changed if not set, execute is only applied to directories and executable files if recursive
+    pub default_permissions: Option<Vec<Permission>>, // Inherited by new entries, only set on directories
+    pub owner: Option<String>, // User name (or id) in the scope, owner is not changed if not set
+    pub group: Option<String>, // Group name (or id) in the scope, group is not changed if not set
+    pub recursive: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct File {
     pub content: Output,
+    pub etag: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -59,6 +164,70 @@ pub struct Directory {
     pub unknown: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct Exists {
+    pub exists: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Stat {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub accessed: i64,
+    pub modified: i64,
+    pub changed: i64,
+    pub created: Option<i64>, // Not supported by all filesystems
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub owner: Option<String>, // User name in the scope
+    pub group: Option<String>, // Group name in the scope
+    pub symlink_target: Option<Output>,
+    pub permissions: Option<Vec<Permission>>, // None for symlinks, which have no ACL
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub checksum: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DirectoryListing {
+    pub entries: Vec<DirectoryEntry>,
+    pub total: usize,    // Amount of entries before offset and limit are applied
+    pub truncated: bool, // Whether entries were left out, as the listing hit the entry cap
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DirectoryEntry {
+    pub name: Output,
+    pub path: Output, // Relative to the listed directory
+    pub kind: EntryKind,
+    pub size: u64,
+    pub modified: i64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub symlink_target: Option<Output>,
+    pub inode: u64,
+    pub hardlinks: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum EntryKind {
+    Directory,
+    File,
+    Symlink,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PermissionFailure {
+    pub path: String,
+    pub error: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Entity {
     User(u32),
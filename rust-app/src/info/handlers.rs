@@ -1,5 +1,5 @@
 use std::{
-    fs::read_to_string,
+    fs::{canonicalize, read_dir, read_link, read_to_string},
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
@@ -8,12 +8,14 @@ use std::{
 use actix_web::{HttpResponse, Responder, get, web};
 
 use crate::{
-    info::models::{Flake, FlakeMetadata, FlakeQuery, Group, User},
+    config::models::Device,
+    info::models::{Flake, FlakeMetadata, FlakeQuery, Gpu, Group, User},
     utils::{
         command::{CommandExecutionMode, execute_command},
         env::{containerstate, nix},
         error::ResponseError,
         output::Output,
+        scope::Scope,
     },
 };
 
@@ -61,12 +63,10 @@ async fn flake(query: web::Query<FlakeQuery>) -> impl Responder {
 }
 
 #[get("/users/{scope}/users")]
-async fn users(path: web::Path<String>) -> impl Responder {
-    let scope = path.into_inner();
-    let prefix = if scope.starts_with("container:") {
-        Some(containerstate().join(scope.replace("container:", "")))
-    } else {
-        None
+async fn users(path: web::Path<Scope>) -> impl Responder {
+    let prefix = match path.into_inner() {
+        Scope::Container(container_id) => Some(containerstate().join(container_id)),
+        Scope::Host => None,
     };
 
     match get_users(prefix) {
@@ -76,12 +76,10 @@ async fn users(path: web::Path<String>) -> impl Responder {
 }
 
 #[get("/users/{scope}/groups")]
-async fn groups(path: web::Path<String>) -> impl Responder {
-    let scope = path.into_inner();
-    let prefix = if scope.starts_with("container:") {
-        Some(containerstate().join(scope.replace("container:", "")))
-    } else {
-        None
+async fn groups(path: web::Path<Scope>) -> impl Responder {
+    let prefix = match path.into_inner() {
+        Scope::Container(container_id) => Some(containerstate().join(container_id)),
+        Scope::Host => None,
     };
 
     match get_groups(prefix) {
@@ -90,6 +88,14 @@ async fn groups(path: web::Path<String>) -> impl Responder {
     }
 }
 
+#[get("/gpus")]
+async fn gpus() -> impl Responder {
+    match get_gpus() {
+        Ok(host_gpus) => HttpResponse::Ok().json(host_gpus),
+        Err(e) => HttpResponse::InternalServerError().json(e),
+    }
+}
+
 impl FromStr for User {
     type Err = ResponseError;
 
@@ -233,3 +239,124 @@ pub fn get_groups(prefix: Option<PathBuf>) -> Result<Vec<Group>, ResponseError>
         .map(Group::from_str)
         .collect::<Result<Vec<Group>, ResponseError>>()
 }
+
+pub fn get_gpus() -> Result<Vec<Gpu>, ResponseError> {
+    let mut host_gpus: Vec<Gpu> = vec![];
+
+    // DRM devices (AMD, Intel, NVIDIA with nvidia-drm)
+    let drm = Path::new("/sys/class/drm");
+    if drm.exists() {
+        let mut nodes = match read_dir(drm) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect::<Vec<String>>(),
+            Err(e) => {
+                return Err(ResponseError::new(format!(
+                    "Could not read {}: {}",
+                    drm.display(),
+                    e
+                )));
+            }
+        };
+        nodes.sort();
+
+        for node in nodes {
+            // Connectors (e.g. card0-HDMI-A-1) are not device nodes
+            let device = if let Some(id) = node.strip_prefix("renderD")
+                && let Ok(id) = id.parse::<u32>()
+            {
+                Device::RenderNode(id)
+            } else if node.starts_with("card") && !node.contains("-") {
+                Device::Char(format!("/dev/dri/{}", node))
+            } else {
+                continue;
+            };
+
+            let Some(pci_address) = canonicalize(drm.join(&node).join("device"))
+                .ok()
+                .and_then(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            else {
+                continue;
+            };
+            get_gpu(&mut host_gpus, &pci_address).devices.push(device);
+        }
+    }
+
+    // Proprietary NVIDIA driver
+    if let Ok(entries) = read_dir("/proc/driver/nvidia/gpus") {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let pci_address = entry.file_name().to_string_lossy().to_string();
+            let information = read_to_string(entry.path().join("information")).unwrap_or_default();
+            let field = |name: &str| {
+                information.lines().find_map(|line| {
+                    let (key, value) = line.split_once(":")?;
+                    (key.trim() == name).then(|| value.trim().to_string())
+                })
+            };
+
+            let model = field("Model");
+            let minor = field("Device Minor").and_then(|minor| minor.parse::<u64>().ok());
+            let gpu = get_gpu(&mut host_gpus, &pci_address);
+            if model.is_some() {
+                gpu.model = model;
+            }
+            gpu.nvidia_gpu = minor;
+            if let Some(minor) = minor {
+                gpu.devices
+                    .push(Device::Char(format!("/dev/nvidia{}", minor)));
+            }
+        }
+    }
+
+    // AMD compute interface, shared by all AMD GPUs
+    if Path::new("/dev/kfd").exists() {
+        host_gpus
+            .iter_mut()
+            .filter(|gpu| gpu.driver.as_deref() == Some("amdgpu"))
+            .for_each(|gpu| gpu.devices.push(Device::Kfd));
+    }
+
+    Ok(host_gpus)
+}
+
+fn get_gpu<'a>(host_gpus: &'a mut Vec<Gpu>, pci_address: &str) -> &'a mut Gpu {
+    let index = match host_gpus
+        .iter()
+        .position(|gpu| gpu.pci_address == pci_address)
+    {
+        Some(index) => index,
+        None => {
+            let path = Path::new("/sys/bus/pci/devices").join(pci_address);
+            let read = |file: &str| {
+                read_to_string(path.join(file))
+                    .ok()
+                    .map(|value| value.trim().to_string())
+                    .filter(|value| !value.is_empty())
+            };
+
+            let vendor_id = read("vendor").unwrap_or_default();
+            host_gpus.push(Gpu {
+                pci_address: pci_address.to_string(),
+                vendor: match vendor_id.as_str() {
+                    "0x10de" => "NVIDIA",
+                    "0x1002" => "AMD",
+                    "0x8086" => "Intel",
+                    _ => "Unknown",
+                }
+                .to_string(),
+                vendor_id,
+                device_id: read("device").unwrap_or_default(),
+                model: read("product_name"),
+                driver: read_link(path.join("driver"))
+                    .ok()
+                    .and_then(|driver| Some(driver.file_name()?.to_string_lossy().to_string())),
+                devices: vec![],
+                nvidia_gpu: None,
+            });
+            host_gpus.len() - 1
+        }
+    };
+
+    &mut host_gpus[index]
+}
@@ -11,4 +11,5 @@ pub fn configure(cfg: &mut ServiceConfig) {
     cfg.service(handlers::flake);
     cfg.service(handlers::users);
     cfg.service(handlers::groups);
+    cfg.service(handlers::gpus);
 }
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::config::models::Device;
+
 #[derive(Serialize, Deserialize)]
 pub struct FlakeQuery {
     pub flake: String,
@@ -34,3 +36,15 @@ pub struct Group {
     pub id: u32,
     pub members: Vec<String>,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct Gpu {
+    pub pci_address: String,
+    pub vendor: String,
+    pub vendor_id: String,
+    pub device_id: String,
+    pub model: Option<String>,
+    pub driver: Option<String>,
+    pub devices: Vec<Device>, // Device nodes that can be passed to a container
+    pub nvidia_gpu: Option<u64>, // Id to use for nvidia_gpus (proprietary NVIDIA driver)
+}
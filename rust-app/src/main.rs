@@ -5,12 +5,14 @@ use std::{
 };
 
 use actix_cors::Cors;
-use actix_web::{App, HttpServer, web};
+use actix_web::{App, HttpResponse, HttpServer, error::InternalError, web};
 use posix_acl::{ACL_READ, ACL_WRITE, PosixACL, Qualifier};
 use usage::models::AppData as ResourceUsageAppData;
 use utils::env::{
-    backupdir, buildcores, commandstream, containerconfig, containerprofile, containersettings,
-    containerstate, datadir, e2fsprogs, nix, nixosrebuild, osdir, socket, systemd,
+    archivelimit, b3sum, backupdir, buildcores, commandstream, containerconfig, containerprofile,
+    containersettings, containerstate, coreutils, datadir, e2fsprogs, gnutar, hostfileallow,
+    hostfiledeny, libarchive, nix, nixosrebuild, osdir, socket, systemd, systemprofile,
+    templatedir, volumedir, zstd,
 };
 
 use crate::{info::handlers::get_groups, utils::error::ResponseError};
@@ -80,6 +82,17 @@ async fn main() -> std::io::Result<()> {
             )
         });
     }
+    {
+        let dir = volumedir();
+        create_dir_all(&dir)
+            .unwrap_or_else(|e| panic!("Could not create volume dir at {}: {}", dir.display(), e));
+    }
+    {
+        let dir = templatedir();
+        create_dir_all(&dir).unwrap_or_else(|e| {
+            panic!("Could not create template dir at {}: {}", dir.display(), e)
+        });
+    }
     {
         let dir = backupdir();
         create_dir_all(&dir)
@@ -101,17 +114,28 @@ async fn main() -> std::io::Result<()> {
     log::info!("SOCKET {}", socket().display());
     log::info!("DATADIR {}", datadir().display());
     log::info!("OSDIR {}", osdir());
+    log::info!("SYSTEMPROFILE {}", systemprofile().display());
     log::info!("CONTAINERSETTINGS {}", containersettings().display());
     log::info!("CONTAINERSTATE {}", containerstate().display());
     log::info!("CONTAINERPROFILE {}", containerprofile().display());
     log::info!("CONTAINERCONFIG {}", containerconfig().display());
+    log::info!("VOLUMEDIR {}", volumedir().display());
+    log::info!("TEMPLATEDIR {}", templatedir().display());
     log::info!("BACKUPDIR {}", backupdir().display());
+    log::info!("HOSTFILEALLOW {:?}", hostfileallow());
+    log::info!("HOSTFILEDENY {:?}", hostfiledeny());
     log::info!("COMMANDSTREAM {}", commandstream().display());
     log::info!("BUILDCORES {}", buildcores());
+    log::info!("ARCHIVELIMIT {}", archivelimit());
     log::info!("NIX {}", nix());
     log::info!("NIXOSREBUILD {}", nixosrebuild());
     log::info!("SYSTEMD {}", systemd());
     log::info!("E2FSPROGS {}", e2fsprogs());
+    log::info!("COREUTILS {}", coreutils());
+    log::info!("GNUTAR {}", gnutar());
+    log::info!("ZSTD {}", zstd());
+    log::info!("LIBARCHIVE {}", libarchive());
+    log::info!("B3SUM {}", b3sum());
 
     // Set socket permissions
     let path: std::path::PathBuf = socket();
@@ -152,11 +176,19 @@ async fn main() -> std::io::Result<()> {
         )
     });
 
+    // Start background tasks
+    config::scheduler::start();
+
     // Start server
     HttpServer::new(move || {
         App::new()
             .wrap(Cors::permissive())
             .app_data(web::Data::new(ResourceUsageAppData::default()))
+            .app_data(web::PathConfig::default().error_handler(|e, _| {
+                // Invalid path segments (e.g. container ids) are a client error
+                let response = HttpResponse::BadRequest().json(ResponseError::new(e.to_string()));
+                InternalError::from_response(e, response).into()
+            }))
             .service(web::scope(&config::scope()).configure(config::configure))
             .service(web::scope(&file::scope()).configure(file::configure))
             .service(web::scope(&info::scope()).configure(info::configure))
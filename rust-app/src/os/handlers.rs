@@ -1,18 +1,26 @@
 use std::{
-    fs::{read_to_string, write},
+    fs::{read_dir, read_to_string, write},
     path::Path,
     process::Command,
 };
 
 use actix_web::{HttpResponse, Responder, get, post, web};
+use serde_json::json;
 
 use crate::{
-    os::models::{OSChange, OSConfiguration},
-    request::{handlers::return_request_id, models::RequestIdResult},
+    os::models::{OSChange, OSConfiguration, OSGeneration, OSGenerationsGc},
+    request::{
+        handlers::return_request_id,
+        models::{RequestId, RequestIdResult},
+    },
     utils::{
+        closure::closure_diff,
         command::{CommandExecutionMode, execute_command},
-        env::{nix, nixosrebuild, osdir, systemd},
+        env::{nix, nixosrebuild, osdir, systemd, systemprofile},
         error::ResponseError,
+        fs::{FileSnapshot, with_build_dir},
+        mode::ChangeMode,
+        profile::{self, current_generation, list_generations},
     },
 };
 
@@ -68,65 +76,77 @@ async fn get() -> impl Responder {
 async fn set(change: web::Json<OSChange>) -> impl Responder {
     return_request_id(Box::new(move |request_id| {
         log::info!("Performing OS change: {:?}", change);
+        let mode = change.mode.unwrap_or(ChangeMode::Switch);
         let osdir = osdir();
         let path = Path::new(&osdir);
 
-        if let Some(flake) = &change.flake {
-            let path = path.join("flake.nix");
-            if let Err(e) = write(&path, flake) {
-                return RequestIdResult::Error {
-                    error: format!("Error writing OS flake to {}: {}", path.display(), e),
-                };
-            }
+        // Tested changes also stay out of the active config, so a reboot goes back to it
+        if mode == ChangeMode::Build || mode == ChangeMode::Test {
+            return with_build_dir(path, |build_dir| {
+                apply_change(&change, build_dir, mode, request_id)
+            });
         }
 
-        for (name, content) in [
-            ("flake.nix", &change.flake),
-            ("xnode-owner", &change.xnode_owner),
-            ("domain", &change.domain),
-            ("acme-email", &change.acme_email),
-            ("user-passwd", &change.user_passwd),
-        ] {
-            if let Some(content) = content {
-                let path = path.join(name);
-                if let Err(e) = write(&path, content) {
-                    return RequestIdResult::Error {
-                        error: format!("Error writing {} to {}: {}", content, path.display(), e),
-                    };
-                }
+        let snapshot = match FileSnapshot::new(
+            [
+                "flake.nix",
+                "flake.lock",
+                "xnode-owner",
+                "domain",
+                "acme-email",
+                "user-passwd",
+            ]
+            .map(|name| path.join(name)),
+        ) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                return RequestIdResult::Error {
+                    error: format!("Error reading current OS config: {}", e),
+                };
             }
-        }
+        };
 
-        if let Some(update_inputs) = &change.update_inputs {
-            let mut command = Command::new(format!("{}nix", nix()));
-            command
-                .env("NIX_REMOTE", "daemon")
-                .arg("flake")
-                .arg("update");
-            for input in update_inputs {
-                command.arg(input);
+        let system_profile = systemprofile().join("system");
+        let previous = current_generation(&system_profile);
+        let result = apply_change(&change, path, mode, request_id);
+        if let RequestIdResult::Error { error } = &result {
+            // Activation can fail (e.g. a unit not starting) after the profile already moved to the new config
+            if current_generation(&system_profile) != previous {
+                log::info!("Keeping new OS config, the system profile already switched to it");
+                return result;
             }
-            command.arg("--flake").arg(path);
-            if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            if let Err(e) = snapshot.restore() {
                 return RequestIdResult::Error {
-                    error: format!("Error updating OS flake: {}", e),
+                    error: format!("{}. Restoring previous OS config failed: {}", error, e),
                 };
             }
+            log::info!("Restored previous OS config after failed change");
         }
 
-        let mut command = Command::new(format!("{}nixos-rebuild", nixosrebuild()));
-        command
-            .env("NIX_REMOTE", "daemon")
-            .arg("switch")
-            .arg("--flake")
-            .arg(path);
-        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
-            return RequestIdResult::Error {
-                error: format!("Error switching to new OS config: {}", e),
-            };
-        }
+        result
+    }))
+}
 
-        RequestIdResult::Success { body: None }
+#[post("/diff")]
+async fn diff(change: web::Json<OSChange>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        with_build_dir(Path::new(&osdir()), |build_dir| {
+            if let RequestIdResult::Error { error } =
+                apply_change(&change, build_dir, ChangeMode::Build, request_id)
+            {
+                return RequestIdResult::Error { error };
+            }
+
+            match closure_diff(
+                Some(Path::new("/run/current-system")),
+                &build_dir.join("result"),
+            ) {
+                Ok(diff) => RequestIdResult::Success {
+                    body: Some(json!(diff).to_string()),
+                },
+                Err(error) => RequestIdResult::Error { error },
+            }
+        })
     }))
 }
 
@@ -144,3 +164,197 @@ async fn reboot() -> impl Responder {
         RequestIdResult::Success { body: None }
     }))
 }
+
+#[get("/generations")]
+async fn generations() -> impl Responder {
+    let path = systemprofile();
+
+    match list_generations(&path.join("system")) {
+        Ok(generations) => {
+            let response: Vec<OSGeneration> = generations
+                .into_iter()
+                .map(|generation| {
+                    let toplevel = &generation.path;
+                    let nixos_version = read_to_string(toplevel.join("nixos-version")).ok();
+                    let kernel =
+                        read_dir(toplevel.join("kernel-modules").join("lib").join("modules"))
+                            .ok()
+                            .and_then(|mut modules| modules.find_map(|m| m.ok()))
+                            .and_then(|m| m.file_name().into_string().ok());
+                    OSGeneration {
+                        number: generation.number,
+                        created: generation.created,
+                        nixos_version,
+                        kernel,
+                        current: generation.current,
+                    }
+                })
+                .collect();
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(ResponseError::new(format!(
+            "Error reading system profile dir {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+#[post("/rollback")]
+async fn rollback() -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        switch_generation_profile(None, request_id)
+    }))
+}
+
+#[post("/switch-generation/{generation}")]
+async fn switch_generation(path: web::Path<u64>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let generation = path.into_inner();
+        switch_generation_profile(Some(generation), request_id)
+    }))
+}
+
+#[post("/generations/gc")]
+async fn generations_gc(gc: web::Json<OSGenerationsGc>) -> impl Responder {
+    return_request_id(Box::new(move |request_id| {
+        let system_profile = systemprofile().join("system");
+
+        let mut command = Command::new(format!("{}nix-env", nix()));
+        command
+            .env("NIX_REMOTE", "daemon")
+            .arg("--profile")
+            .arg(&system_profile)
+            .arg("--delete-generations")
+            .arg(format!("+{}", gc.keep_last.max(1)));
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!("Error deleting old OS generations: {}", e),
+            };
+        }
+
+        let mut command = Command::new(format!("{}nix-store", nix()));
+        command.env("NIX_REMOTE", "daemon").arg("--gc");
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!("Error collecting garbage: {}", e),
+            };
+        }
+
+        // Remove deleted generations from the boot menu
+        let mut command = Command::new(system_profile.join("bin").join("switch-to-configuration"));
+        command.arg("boot");
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!("Error updating boot entries: {}", e),
+            };
+        }
+
+        RequestIdResult::Success { body: None }
+    }))
+}
+
+fn switch_generation_profile(generation: Option<u64>, request_id: RequestId) -> RequestIdResult {
+    let system_profile = systemprofile().join("system");
+    let previous = current_generation(&system_profile);
+
+    if let Err(e) = profile::switch_generation(&system_profile, generation, request_id) {
+        return RequestIdResult::Error {
+            error: format!("Error switching OS generation: {}", e),
+        };
+    }
+
+    let mut command = Command::new(system_profile.join("bin").join("switch-to-configuration"));
+    command.arg("switch");
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+        let mut error = format!("Error activating OS generation: {}", e);
+        // Back to the previous generation, activated again as the failed one could be partially active
+        if let Some(previous) = previous {
+            match profile::switch_generation(&system_profile, Some(previous), request_id) {
+                Ok(()) => {
+                    let mut command =
+                        Command::new(system_profile.join("bin").join("switch-to-configuration"));
+                    command.arg("switch");
+                    if let Err(e) =
+                        execute_command(command, CommandExecutionMode::Stream { request_id })
+                    {
+                        error = format!(
+                            "{}. Activating generation {} again failed: {}",
+                            error, previous, e
+                        );
+                    }
+                }
+                Err(e) => {
+                    error = format!(
+                        "{}. Switching back to generation {} failed: {}",
+                        error, previous, e
+                    );
+                }
+            }
+        }
+        return RequestIdResult::Error { error };
+    }
+
+    RequestIdResult::Success { body: None }
+}
+
+fn apply_change(
+    change: &OSChange,
+    path: &Path,
+    mode: ChangeMode,
+    request_id: RequestId,
+) -> RequestIdResult {
+    for (name, content) in [
+        ("flake.nix", &change.flake),
+        ("xnode-owner", &change.xnode_owner),
+        ("domain", &change.domain),
+        ("acme-email", &change.acme_email),
+        ("user-passwd", &change.user_passwd),
+    ] {
+        if let Some(content) = content {
+            let path = path.join(name);
+            if let Err(e) = write(&path, content) {
+                return RequestIdResult::Error {
+                    error: format!("Error writing {} to {}: {}", content, path.display(), e),
+                };
+            }
+        }
+    }
+
+    if let Some(update_inputs) = &change.update_inputs {
+        let mut command = Command::new(format!("{}nix", nix()));
+        command
+            .env("NIX_REMOTE", "daemon")
+            .arg("flake")
+            .arg("update");
+        for input in update_inputs {
+            command.arg(input);
+        }
+        command.arg("--flake").arg(path);
+        if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+            return RequestIdResult::Error {
+                error: format!("Error updating OS flake: {}", e),
+            };
+        }
+    }
+
+    let mut command = Command::new(format!("{}nixos-rebuild", nixosrebuild()));
+    command
+        .env("NIX_REMOTE", "daemon")
+        .current_dir(path) // nixos-rebuild build creates a result symlink in the working directory
+        .arg(match mode {
+            ChangeMode::Build => "build",
+            ChangeMode::Test => "test",
+            ChangeMode::Boot => "boot",
+            ChangeMode::Switch => "switch",
+        })
+        .arg("--flake")
+        .arg(path);
+    if let Err(e) = execute_command(command, CommandExecutionMode::Stream { request_id }) {
+        return RequestIdResult::Error {
+            error: format!("Error applying new OS config ({:?}): {}", mode, e),
+        };
+    }
+
+    RequestIdResult::Success { body: None }
+}
@@ -10,5 +10,10 @@ pub fn scope() -> String {
 pub fn configure(cfg: &mut ServiceConfig) {
     cfg.service(handlers::get);
     cfg.service(handlers::set);
+    cfg.service(handlers::diff);
     cfg.service(handlers::reboot);
+    cfg.service(handlers::generations);
+    cfg.service(handlers::rollback);
+    cfg.service(handlers::switch_generation);
+    cfg.service(handlers::generations_gc);
 }
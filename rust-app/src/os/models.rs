@@ -1,9 +1,12 @@
 use serde::{Deserialize, Serialize};
 
+use crate::utils::mode::ChangeMode;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OSChange {
     pub flake: Option<String>,
     pub update_inputs: Option<Vec<String>>,
+    pub mode: Option<ChangeMode>,
 
     pub xnode_owner: Option<String>,
     pub domain: Option<String>,
@@ -21,3 +24,17 @@ pub struct OSConfiguration {
     pub acme_email: Option<String>,
     pub user_passwd: Option<String>,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OSGeneration {
+    pub number: u64,
+    pub created: u64, // Epoch time in Seconds
+    pub nixos_version: Option<String>,
+    pub kernel: Option<String>,
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OSGenerationsGc {
+    pub keep_last: u64,
+}
@@ -10,6 +10,7 @@ use crate::{
         env::systemd,
         error::ResponseError,
         output::Output,
+        scope::Scope,
     },
 };
 
@@ -18,7 +19,7 @@ use super::models::{
 };
 
 #[get("/{scope}/list")]
-async fn list(path: web::Path<String>) -> impl Responder {
+async fn list(path: web::Path<Scope>) -> impl Responder {
     let scope = path.into_inner();
     let mut command = Command::new(format!("{}systemctl", systemd()));
     command
@@ -26,10 +27,8 @@ async fn list(path: web::Path<String>) -> impl Responder {
         .arg("--type=service")
         .arg("--output=json")
         .arg("--no-pager");
-    if scope.starts_with("container:") {
-        command
-            .arg("--machine")
-            .arg(scope.replace("container:", ""));
+    if let Scope::Container(container_id) = &scope {
+        command.arg("--machine").arg(container_id.to_string());
     }
     match execute_command(command, CommandExecutionMode::Simple) {
         Ok(output) => match output.into() {
@@ -66,7 +65,7 @@ async fn list(path: web::Path<String>) -> impl Responder {
 }
 
 #[get("/{scope}/{process}/logs")]
-async fn logs(path: web::Path<(String, String)>, query: web::Query<LogQuery>) -> impl Responder {
+async fn logs(path: web::Path<(Scope, String)>, query: web::Query<LogQuery>) -> impl Responder {
     let (scope, process) = path.into_inner();
     let max_logs = query.max.unwrap_or(100);
     let log_level = &query.level;
@@ -82,10 +81,8 @@ async fn logs(path: web::Path<(String, String)>, query: web::Query<LogQuery>) ->
         .arg("__REALTIME_TIMESTAMP,MESSAGE,PRIORITY")
         .arg("--lines")
         .arg(max_logs.to_string());
-    if scope.starts_with("container:") {
-        command
-            .arg("--machine")
-            .arg(scope.replace("container:", ""));
+    if let Scope::Container(container_id) = &scope {
+        command.arg("--machine").arg(container_id.to_string());
     }
     if let Some(level) = log_level {
         command.arg("--priority").arg(
@@ -144,7 +141,7 @@ async fn logs(path: web::Path<(String, String)>, query: web::Query<LogQuery>) ->
 
 #[post("/{scope}/{process}/execute")]
 async fn execute(
-    path: web::Path<(String, String)>,
+    path: web::Path<(Scope, String)>,
     command: web::Json<ProcessCommand>,
 ) -> impl Responder {
     return_request_id(Box::new(move |request_id| {
@@ -157,10 +154,8 @@ async fn execute(
 
         let mut command = Command::new(format!("{}systemctl", systemd()));
         command.arg(systemd_command).arg(&process);
-        if scope.starts_with("container:") {
-            command
-                .arg("--machine")
-                .arg(scope.replace("container:", ""));
+        if let Scope::Container(container_id) = &scope {
+            command.arg("--machine").arg(container_id.to_string());
         }
 
         match execute_command(command, CommandExecutionMode::Stream { request_id }) {
@@ -115,30 +115,41 @@ pub fn return_request_id(
     let request_id = get_request_id();
 
     thread::spawn(move || {
-        let path = commandstream().join(request_id.to_string());
-        if let Err(e) = create_dir_all(&path) {
+        run_request(request_id, thread);
+    });
+
+    HttpResponse::Ok().json(RequestIdResponse { request_id })
+}
+
+// Blocking variant of return_request_id, for requests not originating from an HTTP call
+pub fn execute_request(thread: Box<dyn FnOnce(RequestId) -> RequestIdResult>) -> RequestId {
+    let request_id = get_request_id();
+    run_request(request_id, thread);
+    request_id
+}
+
+fn run_request(request_id: RequestId, thread: Box<dyn FnOnce(RequestId) -> RequestIdResult + '_>) {
+    let path = commandstream().join(request_id.to_string());
+    if let Err(e) = create_dir_all(&path) {
+        log::warn!(
+            "Could not create directory for request {} at {}: {}",
+            request_id,
+            path.display(),
+            e
+        );
+    }
+    let result = thread(request_id);
+    {
+        let path = path.join("result");
+        if let Err(e) = write(&path, json!(result).to_string()) {
             log::warn!(
-                "Could not create directory for request {} at {}: {}",
+                "Could not write result of request {} to {}: {}",
                 request_id,
                 path.display(),
                 e
             );
         }
-        let result = thread(request_id);
-        {
-            let path = path.join("result");
-            if let Err(e) = write(&path, json!(result).to_string()) {
-                log::warn!(
-                    "Could not write result of request {} to {}: {}",
-                    request_id,
-                    path.display(),
-                    e
-                );
-            }
-        }
-    });
-
-    HttpResponse::Ok().json(RequestIdResponse { request_id })
+    }
 }
 
 fn get_request_id() -> RequestId {
@@ -0,0 +1,58 @@
+use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt, path::Path, process::Command};
+
+use crate::utils::{
+    command::{CommandExecutionMode, execute_command},
+    env::{b3sum, coreutils},
+};
+
+pub fn sha256(path: &Path) -> Result<String, String> {
+    let mut command = Command::new(format!("{}sha256sum", coreutils()));
+    command.arg("--binary").arg("-");
+    checksum(path, command, "sha256")
+}
+
+pub fn blake3(path: &Path) -> Result<String, String> {
+    let mut command = Command::new(format!("{}b3sum", b3sum()));
+    command.arg("-");
+    checksum(path, command, "blake3")
+}
+
+fn checksum(path: &Path, mut command: Command, algorithm: &str) -> Result<String, String> {
+    // Non-blocking, so opening a fifo does not wait for a writer
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|e| format!("Error opening {} for checksum: {}", path.display(), e))?;
+    match file.metadata() {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return Err(format!("{} is not a regular file", path.display())),
+        Err(e) => {
+            return Err(format!(
+                "Error getting metadata of {} for checksum: {}",
+                path.display(),
+                e
+            ));
+        }
+    }
+
+    // Read from stdin, output is "<hash>  -"
+    command.stdin(file);
+    match execute_command(command, CommandExecutionMode::Simple) {
+        Ok(output) => String::from_utf8_lossy(&output)
+            .split_whitespace()
+            .next()
+            .map(|hash| hash.to_string())
+            .ok_or(format!(
+                "No {} checksum output for {}",
+                algorithm,
+                path.display()
+            )),
+        Err(e) => Err(format!(
+            "Error calculating {} checksum of {}: {}",
+            algorithm,
+            path.display(),
+            e
+        )),
+    }
+}
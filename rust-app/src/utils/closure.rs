@@ -0,0 +1,141 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::{
+    command::{CommandExecutionMode, execute_command},
+    env::nix,
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct PackageChange {
+    pub name: String,
+    pub old_versions: Vec<String>,
+    pub new_versions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClosureDiff {
+    pub added: Vec<PackageChange>,
+    pub removed: Vec<PackageChange>,
+    pub upgraded: Vec<PackageChange>, // Any version change, including downgrades
+    pub closure_size: u64,            // Bytes
+    pub closure_size_delta: i64,      // Bytes
+}
+
+pub fn closure_diff(current: Option<&Path>, new: &Path) -> Result<ClosureDiff, String> {
+    let current = match current {
+        Some(current) => closure(current)?,
+        None => HashMap::new(),
+    };
+    let new = closure(new)?;
+
+    let current_packages = packages(&current);
+    let new_packages = packages(&new);
+    let mut added = vec![];
+    let mut removed = vec![];
+    let mut upgraded = vec![];
+    let names: BTreeSet<&String> = current_packages.keys().chain(new_packages.keys()).collect();
+    for name in names {
+        let old_versions = current_packages.get(name);
+        let new_versions = new_packages.get(name);
+        if old_versions == new_versions {
+            continue;
+        }
+
+        let change = PackageChange {
+            name: name.to_string(),
+            old_versions: old_versions
+                .map(|versions| versions.iter().cloned().collect())
+                .unwrap_or_default(),
+            new_versions: new_versions
+                .map(|versions| versions.iter().cloned().collect())
+                .unwrap_or_default(),
+        };
+        match (old_versions, new_versions) {
+            (None, Some(_)) => added.push(change),
+            (Some(_), None) => removed.push(change),
+            _ => upgraded.push(change),
+        }
+    }
+
+    let current_size: u64 = current.values().sum();
+    let new_size: u64 = new.values().sum();
+    Ok(ClosureDiff {
+        added,
+        removed,
+        upgraded,
+        closure_size: new_size,
+        closure_size_delta: new_size as i64 - current_size as i64,
+    })
+}
+
+// Store paths in the closure of path, with their nar size
+fn closure(path: &Path) -> Result<HashMap<String, u64>, String> {
+    let mut command = Command::new(format!("{}nix", nix()));
+    command
+        .env("NIX_REMOTE", "daemon")
+        .arg("path-info")
+        .arg("--recursive")
+        .arg("--json")
+        .arg(path);
+    let output = execute_command(command, CommandExecutionMode::Simple)
+        .map_err(|e| format!("Error getting closure of {}: {}", path.display(), e))?;
+    let info: Value = serde_json::from_slice(&output)
+        .map_err(|e| format!("Closure of {} could not be parsed: {}", path.display(), e))?;
+
+    // Older nix versions return a list of path infos, newer an object keyed by store path
+    let entries: Vec<(String, &Value)> = match &info {
+        Value::Array(entries) => entries
+            .iter()
+            .filter_map(|entry| Some((entry.get("path")?.as_str()?.to_string(), entry)))
+            .collect(),
+        Value::Object(entries) => entries
+            .iter()
+            .map(|(path, entry)| (path.to_string(), entry))
+            .collect(),
+        _ => vec![],
+    };
+
+    Ok(entries
+        .into_iter()
+        .map(|(path, entry)| {
+            let nar_size = entry.get("narSize").and_then(|s| s.as_u64()).unwrap_or(0);
+            (path, nar_size)
+        })
+        .collect())
+}
+
+fn packages(closure: &HashMap<String, u64>) -> BTreeMap<String, BTreeSet<String>> {
+    let mut packages: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for path in closure.keys() {
+        let (name, version) = parse_store_path(path);
+        packages.entry(name).or_default().insert(version);
+    }
+    packages
+}
+
+// Splits /nix/store/<hash>-<name>-<version> like builtins.parseDrvName: the version starts at the first dash followed by a digit
+fn parse_store_path(path: &str) -> (String, String) {
+    let base_name = path.rsplit('/').next().unwrap_or(path);
+    let name_version = base_name
+        .split_once('-')
+        .map(|(_hash, name_version)| name_version)
+        .unwrap_or(base_name);
+
+    let bytes = name_version.as_bytes();
+    match (0..bytes.len().saturating_sub(1))
+        .find(|i| bytes[*i] == b'-' && bytes[i + 1].is_ascii_digit())
+    {
+        Some(i) => (
+            name_version[..i].to_string(),
+            name_version[i + 1..].to_string(),
+        ),
+        None => (name_version.to_string(), String::new()),
+    }
+}
@@ -24,6 +24,12 @@ pub fn osdir() -> String {
     env_var("OSDIR").unwrap_or("/etc/nixos".to_string())
 }
 
+pub fn systemprofile() -> PathBuf {
+    env_var("SYSTEMPROFILE")
+        .map(|d| Path::new(&d).to_path_buf())
+        .unwrap_or(Path::new("/nix/var/nix/profiles").to_path_buf())
+}
+
 pub fn containersettings() -> PathBuf {
     env_var("CONTAINERSETTINGS")
         .map(|d| Path::new(&d).to_path_buf())
@@ -54,12 +60,41 @@ pub fn systemdconfig() -> PathBuf {
         .unwrap_or(Path::new("/etc/systemd/system.control").to_path_buf())
 }
 
+pub fn volumedir() -> PathBuf {
+    env_var("VOLUMEDIR")
+        .map(|d| Path::new(&d).to_path_buf())
+        .unwrap_or(Path::new(&datadir()).join("volumes"))
+}
+
+pub fn templatedir() -> PathBuf {
+    env_var("TEMPLATEDIR")
+        .map(|d| Path::new(&d).to_path_buf())
+        .unwrap_or(Path::new(&datadir()).join("templates"))
+}
+
 pub fn backupdir() -> PathBuf {
     env_var("BACKUPDIR")
         .map(|d| Path::new(&d).to_path_buf())
         .unwrap_or(Path::new(&datadir()).join("backups"))
 }
 
+pub fn hostfileallow() -> Vec<PathBuf> {
+    env_var("HOSTFILEALLOW")
+        .map(|d| d.split(":").map(PathBuf::from).collect())
+        .unwrap_or(vec![Path::new("/").to_path_buf()])
+}
+
+pub fn hostfiledeny() -> Vec<PathBuf> {
+    env_var("HOSTFILEDENY")
+        .map(|d| {
+            d.split(":")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 pub fn commandstream() -> PathBuf {
     env_var("COMMANDSTREAM")
         .map(|d| Path::new(&d).to_path_buf())
@@ -78,6 +113,19 @@ pub fn buildcores() -> u64 {
         .unwrap_or(0)
 }
 
+// Size in bytes an uploaded archive (to extract or import) can have
+pub fn archivelimit() -> u64 {
+    env_var("ARCHIVELIMIT")
+        .and_then(|s| {
+            str::parse::<u64>(&s)
+                .inspect_err(|e| {
+                    log::error!("Could not parse ARCHIVELIMIT to u64: {}", e);
+                })
+                .ok()
+        })
+        .unwrap_or(16 * 1024 * 1024 * 1024)
+}
+
 pub fn nix() -> String {
     env_var("NIX").unwrap_or("".to_string())
 }
@@ -93,3 +141,23 @@ pub fn systemd() -> String {
 pub fn e2fsprogs() -> String {
     env_var("E2FSPROGS").unwrap_or("".to_string())
 }
+
+pub fn coreutils() -> String {
+    env_var("COREUTILS").unwrap_or("".to_string())
+}
+
+pub fn gnutar() -> String {
+    env_var("GNUTAR").unwrap_or("".to_string())
+}
+
+pub fn zstd() -> String {
+    env_var("ZSTD").unwrap_or("".to_string())
+}
+
+pub fn libarchive() -> String {
+    env_var("LIBARCHIVE").unwrap_or("".to_string())
+}
+
+pub fn b3sum() -> String {
+    env_var("B3SUM").unwrap_or("".to_string())
+}
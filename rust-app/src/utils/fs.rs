@@ -1,5 +1,21 @@
-use std::fs::{copy, create_dir_all, read_dir};
-use std::path::Path;
+use std::ffi::{CString, OsString};
+use std::fs::{
+    DirBuilder, File, Metadata, OpenOptions, Permissions, copy, create_dir, create_dir_all, read,
+    read_dir, read_link, remove_dir_all, remove_file, rename, set_permissions, symlink_metadata,
+    write,
+};
+use std::io::{Error, ErrorKind, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt, lchown, symlink,
+};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use posix_acl::PosixACL;
+
+use crate::{request::models::RequestIdResult, utils::env::datadir};
 
 pub fn copy_dir_all(
     source: impl AsRef<Path>,
@@ -17,3 +33,484 @@ pub fn copy_dir_all(
     }
     Ok(())
 }
+
+// New directory only accessible by the service, instead of the shared /tmp where other users can plant entries
+pub fn private_temp_dir(prefix: &str) -> std::io::Result<PathBuf> {
+    let parent = datadir().join("tmp");
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&parent)?;
+    set_permissions(&parent, Permissions::from_mode(0o700))?;
+
+    loop {
+        let path = parent.join(format!(
+            "{}-{}",
+            prefix,
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        ));
+        match DirBuilder::new().mode(0o700).create(&path) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            result => return result.map(|()| path),
+        }
+    }
+}
+
+// Build in a private copy of dir (empty if dir does not exist yet), leaving dir itself untouched
+pub fn with_build_dir(dir: &Path, build: impl FnOnce(&Path) -> RequestIdResult) -> RequestIdResult {
+    let build_dir = match private_temp_dir("build") {
+        Ok(build_dir) => build_dir,
+        Err(e) => {
+            return RequestIdResult::Error {
+                error: format!("Error creating build dir: {}", e),
+            };
+        }
+    };
+    if dir.exists()
+        && let Err(e) = copy_dir_all(dir, &build_dir)
+    {
+        let _ = remove_dir_all(&build_dir);
+        return RequestIdResult::Error {
+            error: format!(
+                "Error copying {} to build dir {}: {}",
+                dir.display(),
+                build_dir.display(),
+                e
+            ),
+        };
+    }
+
+    let result = build(&build_dir);
+    if let Err(e) = remove_dir_all(&build_dir) {
+        log::warn!("Could not remove build dir {}: {}", build_dir.display(), e);
+    }
+
+    result
+}
+
+// Copy a file, symlink or directory (recursively) without following symlinks, keeping ownership and ACLs
+pub fn copy_preserving(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let metadata = symlink_metadata(source)?;
+    if metadata.file_type().is_symlink() {
+        symlink(read_link(source)?, destination)?;
+        return copy_attributes(source, &metadata, destination);
+    }
+    if !metadata.is_dir() && !metadata.is_file() {
+        return Err(special_file(source));
+    }
+
+    // Everything after this goes through the opened source, so swapping it for a symlink in the meantime has no effect
+    let mut reader = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(source)?;
+    let metadata = reader.metadata()?;
+    let opened = fd_path(&reader);
+    if metadata.is_dir() {
+        create_dir(destination)?;
+        for entry in read_dir(&opened)? {
+            let name = entry?.file_name();
+            copy_preserving(&opened.join(&name), &destination.join(&name))?;
+        }
+    } else if metadata.is_file() {
+        // Never write through an existing file or symlink at the destination
+        let mut writer = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(destination)?;
+        std::io::copy(&mut reader, &mut writer)?;
+    } else {
+        return Err(special_file(source));
+    }
+
+    copy_attributes(&opened, &metadata, destination)
+}
+
+// Path that refers to the already opened file itself, also when its original path has changed since
+pub fn fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+// Refers to the entry at path (relative to dir if given) without following a symlink as last component, not to read or write it
+pub fn open_entry(dir: Option<&File>, path: &Path) -> std::io::Result<File> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let dir = dir.map(|dir| dir.as_raw_fd()).unwrap_or(libc::AT_FDCWD);
+    // SAFETY: path is a valid nul-terminated string that outlives the call
+    let fd = unsafe {
+        libc::openat(
+            dir,
+            path.as_ptr(),
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // SAFETY: fd was just opened and is not owned by anything else
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+// Change owner and/or group of an entry opened with open_entry
+pub fn chown_entry(entry: &File, owner: Option<u32>, group: Option<u32>) -> std::io::Result<()> {
+    // SAFETY: the empty path is a valid nul-terminated string, -1 keeps the owner or group as is
+    let result = unsafe {
+        libc::fchownat(
+            entry.as_raw_fd(),
+            c"".as_ptr(),
+            owner.unwrap_or(u32::MAX),
+            group.unwrap_or(u32::MAX),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn special_file(path: &Path) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("Can not copy special file {}", path.display()),
+    )
+}
+
+// Write to a temporary file next to path and rename it over path, so readers never see a partial file
+pub fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    // Replacing a hardlinked file would split it from its other links
+    if let Ok(metadata) = symlink_metadata(path)
+        && metadata.is_file()
+        && metadata.nlink() > 1
+    {
+        return write_in_place(path, content);
+    }
+
+    let name = path
+        .file_name()
+        .ok_or(Error::new(ErrorKind::InvalidInput, "Path has no file name"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(
+        ".xnode-manager-{}",
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    ));
+    let temp = path.with_file_name(temp_name);
+
+    let result = (|| {
+        // Only accessible by the service until it has the attributes of the file it replaces, before any content is in it
+        let mut writer = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        match symlink_metadata(path) {
+            Ok(metadata) => copy_attributes(path, &metadata, &temp)?,
+            // As a new file created with the default umask
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                set_permissions(&temp, Permissions::from_mode(0o644))?
+            }
+            Err(e) => return Err(e),
+        }
+        writer.write_all(content)?;
+        writer.sync_all()?;
+        rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = remove_file(&temp);
+    }
+    match result {
+        // A mount point (e.g. a file bind mounted into a container) can not be replaced
+        Err(e) if e.kind() == ErrorKind::ResourceBusy => return write_in_place(path, content),
+        result => result?,
+    }
+
+    // Persist the rename itself
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn write_in_place(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut writer = OpenOptions::new().write(true).truncate(true).open(path)?;
+    writer.write_all(content)?;
+    writer.sync_all()
+}
+
+// Owner, mode and ACLs of source (with its metadata) onto destination
+fn copy_attributes(source: &Path, metadata: &Metadata, destination: &Path) -> std::io::Result<()> {
+    let file_type = metadata.file_type();
+    lchown(destination, Some(metadata.uid()), Some(metadata.gid()))?;
+    if !file_type.is_symlink() {
+        // After chown, as changing the owner can clear setuid/setgid bits
+        set_permissions(
+            destination,
+            Permissions::from_mode(metadata.permissions().mode()),
+        )?;
+
+        // Only write ACLs with more than the owner/group/other entries, so filesystems without ACL support work
+        let mut acl = PosixACL::read_acl(source).map_err(Error::other)?;
+        if acl.entries().len() > 3 {
+            acl.write_acl(destination).map_err(Error::other)?;
+        }
+        if file_type.is_dir() {
+            let mut default_acl = PosixACL::read_default_acl(source).map_err(Error::other)?;
+            if !default_acl.entries().is_empty() {
+                default_acl
+                    .write_default_acl(destination)
+                    .map_err(Error::other)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Rename, or copy and remove when the destination is on a different filesystem (e.g. a separate volume)
+// An existing destination is never replaced
+pub fn move_path(source: &Path, destination: &Path) -> std::io::Result<()> {
+    match rename_noreplace(source, destination) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            if symlink_metadata(destination).is_ok() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists", destination.display()),
+                ));
+            }
+            if let Err(e) = copy_preserving(source, destination) {
+                // Only clean up what this copy created, not an entry that appeared in the meantime
+                if e.kind() != ErrorKind::AlreadyExists {
+                    let _ = remove_path(destination);
+                }
+                return Err(e);
+            }
+            remove_path(source)
+        }
+        result => result,
+    }
+}
+
+fn rename_noreplace(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let source = CString::new(source.as_os_str().as_bytes())?;
+    let destination = CString::new(destination.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid nul-terminated strings that outlive the call
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            source.as_ptr(),
+            libc::AT_FDCWD,
+            destination.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    if symlink_metadata(path)?.is_dir() {
+        remove_dir_all(path)
+    } else {
+        remove_file(path)
+    }
+}
+
+// Contents of files before a change, to put them back in case the change fails
+pub struct FileSnapshot {
+    files: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+impl FileSnapshot {
+    pub fn new(paths: impl IntoIterator<Item = PathBuf>) -> std::io::Result<Self> {
+        let files = paths
+            .into_iter()
+            .map(|path| match read(&path) {
+                Ok(content) => Ok((path, Some(content))),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok((path, None)),
+                Err(e) => Err(e),
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self { files })
+    }
+
+    pub fn restore(&self) -> std::io::Result<()> {
+        for (path, content) in &self.files {
+            match content {
+                Some(content) => write(path, content)?,
+                None => {
+                    if path.exists() {
+                        remove_file(path)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// An entry resolved inside root (as if root was /) by the kernel with openat2, so .. and symlinks (including absolute ones) can not leave root
+// Its parent dir (and the entry itself if it exists) stay opened, operations go through those instead of resolving the path again
+// A component swapped for a symlink after resolving has no effect on them
+pub struct ResolvedPath {
+    dir: File,
+    name: Option<OsString>,
+    entry: Option<File>,
+    real: PathBuf,
+}
+
+impl ResolvedPath {
+    // A symlink as last component is followed (inside root) if follow_last, otherwise the symlink itself is the entry
+    pub fn new(root: &Path, path: &Path, follow_last: bool) -> std::io::Result<Self> {
+        let root = open_entry(None, root)?;
+        let mut path = path.to_path_buf();
+        let mut links = 0;
+        loop {
+            let (parent, name) = match path.components().next_back() {
+                Some(Component::Normal(name)) => (
+                    path.parent().unwrap_or(Path::new("")).to_path_buf(),
+                    Some(name.to_os_string()),
+                ),
+                _ => (path.clone(), None),
+            };
+            let dir = open_in_root(&root, &parent)?;
+            let Some(name) = name else {
+                let entry = dir.try_clone()?;
+                let real = read_link(fd_path(&dir))?;
+                return Ok(Self {
+                    dir,
+                    name: None,
+                    entry: Some(entry),
+                    real,
+                });
+            };
+
+            let entry = match open_entry(Some(&dir), Path::new(&name)) {
+                Ok(entry) => Some(entry),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            if follow_last
+                && let Some(entry) = &entry
+                && entry.metadata()?.is_symlink()
+            {
+                links += 1;
+                if links > 40 {
+                    return Err(Error::other(format!(
+                        "Too many levels of symbolic links in {}",
+                        path.display()
+                    )));
+                }
+
+                // Relative to the dir of the symlink, absolute ones from root
+                path = parent.join(read_link_at(&dir, &name)?);
+                continue;
+            }
+
+            let real = read_link(fd_path(&dir))?.join(&name);
+            return Ok(Self {
+                dir,
+                name: Some(name),
+                entry,
+                real,
+            });
+        }
+    }
+
+    // To create, replace or remove the entry (or act on a symlink itself), only its name is looked up again
+    pub fn path(&self) -> PathBuf {
+        let dir = pinned_path(&self.dir);
+        match &self.name {
+            Some(name) => dir.join(name),
+            None => dir,
+        }
+    }
+
+    // To use the entry as it was resolved (e.g. read a file or list a directory), also from child processes
+    pub fn target(&self) -> std::io::Result<PathBuf> {
+        self.entry
+            .as_ref()
+            .map(pinned_path)
+            .ok_or(Error::from(ErrorKind::NotFound))
+    }
+
+    // Opened like open_entry
+    pub fn entry(&self) -> std::io::Result<File> {
+        self.entry
+            .as_ref()
+            .ok_or(Error::from(ErrorKind::NotFound))?
+            .try_clone()
+    }
+
+    // Metadata of the entry (of the symlink itself if it was not followed)
+    pub fn metadata(&self) -> std::io::Result<Metadata> {
+        self.entry
+            .as_ref()
+            .ok_or(Error::from(ErrorKind::NotFound))?
+            .metadata()
+    }
+
+    // Where the entry is on the host, for messages and comparing paths
+    pub fn real(&self) -> &Path {
+        &self.real
+    }
+
+    pub fn display(&self) -> std::path::Display<'_> {
+        self.real.display()
+    }
+}
+
+// Directory at path below root, which can not be left by .. or symlinks and can not go through magic links in /proc
+fn open_in_root(root: &File, path: &Path) -> std::io::Result<File> {
+    let path = CString::new(if path.as_os_str().is_empty() {
+        b".".as_slice()
+    } else {
+        path.as_os_str().as_bytes()
+    })?;
+    // SAFETY: open_how is plain data, all zero is valid (and needed for fields of newer kernels)
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+    // SAFETY: path is a valid nul-terminated string and how a valid open_how, both outlive the call
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // SAFETY: fd was just opened and is not owned by anything else
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd as i32) }))
+}
+
+fn read_link_at(dir: &File, name: &OsString) -> std::io::Result<PathBuf> {
+    read_link(fd_path(dir).join(name))
+}
+
+// Like fd_path, but through the process id, so child processes (e.g. tar) can use it as long as the file stays open
+fn pinned_path(file: &File) -> PathBuf {
+    PathBuf::from(format!(
+        "/proc/{}/fd/{}",
+        std::process::id(),
+        file.as_raw_fd()
+    ))
+}
@@ -0,0 +1,46 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Condvar, Mutex},
+};
+
+// A lock per key (e.g. a path or container id), without keeping anything around for keys nobody holds
+pub struct KeyedLocks<K> {
+    held: Mutex<BTreeSet<K>>,
+    released: Condvar,
+}
+
+impl<K: Ord + Clone> KeyedLocks<K> {
+    pub const fn new() -> Self {
+        Self {
+            held: Mutex::new(BTreeSet::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    // Waits until no one else holds key, which is held until the guard is dropped
+    pub fn lock(&self, key: K) -> KeyedLockGuard<'_, K> {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        while held.contains(&key) {
+            held = self.released.wait(held).unwrap_or_else(|e| e.into_inner());
+        }
+        held.insert(key.clone());
+
+        KeyedLockGuard { locks: self, key }
+    }
+}
+
+pub struct KeyedLockGuard<'a, K: Ord> {
+    locks: &'a KeyedLocks<K>,
+    key: K,
+}
+
+impl<K: Ord> Drop for KeyedLockGuard<'_, K> {
+    fn drop(&mut self) {
+        self.locks
+            .held
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+        self.locks.released.notify_all();
+    }
+}
@@ -1,6 +1,12 @@
+pub mod checksum;
+pub mod closure;
 pub mod command;
 pub mod env;
 pub mod error;
 pub mod fs;
+pub mod lock;
+pub mod mode;
 pub mod output;
-pub mod string;
+pub mod profile;
+pub mod scope;
+pub mod stream;
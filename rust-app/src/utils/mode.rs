@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChangeMode {
+    Build,  // Only build the result, without writing or activating anything
+    Test,   // Activate the result, without making it the default on (container) restart
+    Boot,   // Make the result the default on (container) restart, without activating it
+    Switch, // Activate the result and make it the default
+}
@@ -0,0 +1,100 @@
+use std::{
+    fs::{read_dir, read_link, symlink_metadata},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use crate::{
+    request::models::RequestId,
+    utils::{
+        command::{CommandExecutionMode, execute_command},
+        env::nix,
+    },
+};
+
+pub struct Generation {
+    pub number: u64,
+    pub created: u64, // Unix seconds
+    pub path: PathBuf,
+    pub current: bool,
+}
+
+// Generations are stored as <profile>-<number>-link symlinks next to the profile, sorted by number
+pub fn list_generations(profile: &Path) -> std::io::Result<Vec<Generation>> {
+    let (Some(dir), Some(profile_name)) = (profile.parent(), profile.file_name()) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid profile path {}", profile.display()),
+        ));
+    };
+    let profile_name = profile_name.to_string_lossy();
+    let current = current_generation(profile);
+
+    let mut generations: Vec<Generation> = read_dir(dir)?
+        .filter_map(|f| f.ok())
+        .filter_map(|f| {
+            let number = generation_number(&f.file_name().to_string_lossy(), &profile_name)?;
+            let created = symlink_metadata(f.path())
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            Some(Generation {
+                number,
+                created,
+                path: f.path(),
+                current: current == Some(number),
+            })
+        })
+        .collect();
+    generations.sort_by_key(|g| g.number);
+
+    Ok(generations)
+}
+
+// Generation number of a <profile>-<number>-link name
+fn generation_number(name: &str, profile_name: &str) -> Option<u64> {
+    name.strip_prefix(profile_name)?
+        .strip_prefix("-")?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+// Generation the profile currently points to
+pub fn current_generation(profile: &Path) -> Option<u64> {
+    let link = read_link(profile).ok()?;
+    generation_number(
+        &link.file_name()?.to_string_lossy(),
+        &profile.file_name()?.to_string_lossy(),
+    )
+}
+
+// Point the profile to generation, or to the one before the current generation if not set
+pub fn switch_generation(
+    profile: &Path,
+    generation: Option<u64>,
+    request_id: RequestId,
+) -> Result<(), String> {
+    let mut command = Command::new(format!("{}nix-env", nix()));
+    command
+        .env("NIX_REMOTE", "daemon")
+        .arg("--profile")
+        .arg(profile);
+    match generation {
+        Some(generation) => {
+            command
+                .arg("--switch-generation")
+                .arg(generation.to_string());
+        }
+        None => {
+            command.arg("--rollback");
+        }
+    }
+    execute_command(command, CommandExecutionMode::Stream { request_id })
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,97 @@
+use std::{fmt::Display, ops::Deref, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+// Container name, also used as machine name, hostname and in paths on the host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct ContainerId(String);
+
+impl TryFrom<String> for ContainerId {
+    type Error = String;
+
+    // Letters, digits, - and _ as in machine names, starting with a letter or digit
+    // Dots are left out (not allowed by nixos-container), so hidden dirs next to containers never match
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        if id.is_empty() || id.len() > 64 {
+            return Err(format!(
+                "Invalid container id {}: should be between 1 and 64 characters",
+                id
+            ));
+        }
+        if !id.starts_with(|c: char| c.is_ascii_alphanumeric())
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "Invalid container id {}: should start with a letter or digit and only contain letters, digits, - and _",
+                id
+            ));
+        }
+
+        Ok(Self(id))
+    }
+}
+
+impl From<ContainerId> for String {
+    fn from(id: ContainerId) -> Self {
+        id.0
+    }
+}
+
+impl Deref for ContainerId {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ContainerId {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl Display for ContainerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// {scope} path segment: container:<id> for a container, anything else for the host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Scope {
+    Host,
+    Container(ContainerId),
+}
+
+impl TryFrom<String> for Scope {
+    type Error = String;
+
+    fn try_from(scope: String) -> Result<Self, Self::Error> {
+        match scope.strip_prefix("container:") {
+            Some(container_id) => {
+                ContainerId::try_from(container_id.to_string()).map(Scope::Container)
+            }
+            None => Ok(Scope::Host),
+        }
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Host => write!(f, "host"),
+            Scope::Container(container_id) => write!(f, "container:{}", container_id),
+        }
+    }
+}
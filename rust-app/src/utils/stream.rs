@@ -0,0 +1,143 @@
+use std::{
+    fs::OpenOptions,
+    io::{Error, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+use actix_web::{
+    http::StatusCode,
+    web::{self, Bytes},
+};
+use futures_util::{Stream, StreamExt, stream};
+
+// Stdout of a running command, ending with an error if the command fails
+struct CommandOutput {
+    child: Child,
+    stdout: ChildStdout,
+    on_finish: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Read for CommandOutput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = self.stdout.read(buf)?;
+        if length == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(Error::other(format!("Command exited with {}", status)));
+            }
+        }
+
+        Ok(length)
+    }
+}
+
+impl Drop for CommandOutput {
+    // Also runs when the client disconnects before the command is done
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some(on_finish) = self.on_finish.take() {
+            on_finish();
+        }
+    }
+}
+
+pub fn stream_command(
+    command: Command,
+    on_finish: Box<dyn FnOnce() + Send>,
+) -> Result<impl Stream<Item = Result<Bytes, Error>>, Error> {
+    command_output(command, on_finish).map(stream_reader)
+}
+
+// Stdout of a command as reader, to transform it before streaming
+pub fn command_output(
+    mut command: Command,
+    on_finish: Box<dyn FnOnce() + Send>,
+) -> Result<impl Read + Send + 'static, Error> {
+    log::info!("Streaming command: {:?}", command);
+
+    let mut child = match command.stdout(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            on_finish();
+            return Err(e);
+        }
+    };
+    let Some(stdout) = child.stdout.take() else {
+        on_finish();
+        return Err(Error::other("Command stdout not captured"));
+    };
+
+    Ok(CommandOutput {
+        child,
+        stdout,
+        on_finish: Some(on_finish),
+    })
+}
+
+// Reads in chunks on the blocking thread pool, for use as response body
+pub fn stream_reader<R: Read + Send + 'static>(
+    reader: R,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        let result = web::block(move || {
+            let mut buffer = vec![0; 64 * 1024];
+            let result = reader.read(&mut buffer).map(|length| {
+                buffer.truncate(length);
+                buffer
+            });
+            (reader, result)
+        })
+        .await;
+
+        match result {
+            Ok((_, Ok(buffer))) if buffer.is_empty() => None,
+            Ok((reader, Ok(buffer))) => Some((Ok(Bytes::from(buffer)), Some(reader))),
+            Ok((_, Err(e))) => Some((Err(e), None)),
+            Err(e) => Some((Err(Error::other(e.to_string())), None)),
+        }
+    })
+}
+
+// Writes a request body to a new file only accessible by the service, on the blocking thread pool
+// Bodies larger than limit (in bytes) are rejected, leaving the partial file for the caller to remove
+pub async fn receive_payload(
+    mut payload: web::Payload,
+    path: &Path,
+    limit: u64,
+) -> Result<(), (StatusCode, String)> {
+    let internal = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let open_path = path.to_path_buf();
+    let mut file = web::block(move || {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&open_path)
+    })
+    .await
+    .map_err(|e| internal(e.to_string()))?
+    .map_err(|e| internal(format!("Error creating {}: {}", path.display(), e)))?;
+
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| internal(e.to_string()))?;
+        size += chunk.len() as u64;
+        if size > limit {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Upload is larger than the limit of {} bytes", limit),
+            ));
+        }
+
+        file = web::block(move || file.write_all(&chunk).map(|()| file))
+            .await
+            .map_err(|e| internal(e.to_string()))?
+            .map_err(|e| internal(format!("Error writing {}: {}", path.display(), e)))?;
+    }
+
+    Ok(())
+}